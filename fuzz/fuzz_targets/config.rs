@@ -0,0 +1,11 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+#![no_main]
+
+use dyndnsd::config::Config;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|contents: &str| {
+	let _ = Config::parse(contents);
+});
@@ -0,0 +1,11 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+#![no_main]
+
+use dyndnsd::process::Ipv6LanPrefix;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|prefix: &str| {
+	let _ = Ipv6LanPrefix::try_from(prefix.to_string());
+});
@@ -0,0 +1,13 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+#![no_main]
+
+use dyndnsd::process::parse_query;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+fuzz_target!(|query: &str| {
+	let aliases = HashMap::from([("ip".to_string(), "ipv4".to_string())]);
+	let _ = parse_query(query, &aliases);
+});
@@ -43,6 +43,11 @@ rec {
   # artifacts from above.
   dyndnsd = craneLib.buildPackage (commonArgs // {
     inherit cargoArtifacts;
+
+    # Only needed by the integration tests
+    postInstall = ''
+      rm "$out/bin/mock-update-program"
+    '';
   });
 
   dyndnsd-systemd-unit = pkgs.runCommand "dyndnsd-systemd-unit" { } ''
@@ -32,6 +32,79 @@ let
           };
         };
       };
+      allow_ipv4 = lib.mkOption {
+        type = lib.types.bool;
+        default = true;
+        description = ''
+          Whether this user may send the `ipv4` parameter.
+          Disable this for users whose domains should only have AAAA records, requests containing the parameter are then rejected.
+        '';
+      };
+      allow_ipv6 = lib.mkOption {
+        type = lib.types.bool;
+        default = true;
+        description = ''
+          Whether this user may send the `ipv6` parameter.
+          Requests containing the parameter are rejected if this is disabled.
+        '';
+      };
+      allow_ipv6lanprefix = lib.mkOption {
+        type = lib.types.bool;
+        default = true;
+        description = ''
+          Whether this user may send the `ipv6lanprefix` and `prefixlen` parameters.
+          Requests containing the parameter are rejected if this is disabled.
+        '';
+      };
+      role = lib.mkOption {
+        type = lib.types.enum [ "user" "admin" ];
+        default = "user";
+        description = ''
+          Admins may update any domain by sending a comma separated list of domains in the `domain` parameter instead of the domains configured for them.
+          The settings of a domain are taken from the admin's own domains, then from the domains of the other users and otherwise default to a TTL of 60 and the IPv6 address being used unchanged.
+        '';
+      };
+      site = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "home";
+        description = ''
+          Name of the site, i.e. uplink, this user shares with other users.
+          When one user of a site sends a LAN prefix, the domains of all users of the site with `ipv6prefixlen = "lan"` are updated with it.
+        '';
+      };
+      allowed_countries = lib.mkOption {
+        type = lib.types.nullOr (lib.types.listOf lib.types.str);
+        default = null;
+        example = [ "NL" ];
+        description = ''
+          ISO 3166-1 codes of the countries this user may send updates from, replacing `geoip.allowed_countries`.
+          Requires `geoip` to be set.
+        '';
+      };
+      quota = {
+        max_domains = lib.mkOption {
+          type = lib.types.nullOr lib.types.ints.positive;
+          default = null;
+          description = ''
+            Number of domains updated per request, sorted by name unless the request lists them.
+          '';
+        };
+        min_ttl = lib.mkOption {
+          type = lib.types.nullOr lib.types.ints.unsigned;
+          default = null;
+          description = ''
+            Lower TTLs of the domains of this user are raised to this.
+          '';
+        };
+        max_updates_per_day = lib.mkOption {
+          type = lib.types.nullOr lib.types.ints.positive;
+          default = null;
+          description = ''
+            Further update requests are refused until a day has passed since the first one.
+          '';
+        };
+      };
     };
   };
 
@@ -46,7 +119,7 @@ let
         '';
       };
       ipv6prefixlen = lib.mkOption {
-        type = lib.types.ints.between 0 128;
+        type = lib.types.either (lib.types.ints.between 0 128) (lib.types.enum [ "lan" ]);
         default = 128;
         example = 56;
         description = ''
@@ -58,10 +131,12 @@ let
           The IPv6 address in the URL query parameter is spliced together with the IPv6 address from the `ipv6suffix` option
           by taking the first `ipv6prefixlen` bits from the IPv6 address from the URL query parameter with the last 128 - `ipv6prefixlen` bits from the `ipv6suffix` option.
           If you do not want to update the IPv6 address, set this to 0.
+          Set this to "lan" to use the prefix and prefix length sent by the router, either as the `ipv6lanprefix` parameter
+          or as the `ipv6` parameter together with the `prefixlen` parameter.
         '';
       };
       ipv6suffix = lib.mkOption {
-        type = lib.types.str;
+        type = lib.types.either lib.types.str lib.types.ints.unsigned;
         default = "::";
         example = "0:0:0:1::5";
         description = ''
@@ -72,6 +147,34 @@ let
           or change the network settings on your server to not randomly generate new addresses.
           The IPv6 address in the URL query parameter is spliced together with the IPv6 address from the `ipv6suffix` option
           by taking the first `ipv6prefixlen` bits from the IPv6 address from the URL query parameter with the last 128 - `ipv6prefixlen` bits from the `ipv6suffix` option.
+          Instead of an IPv6 address, this can also be the number of the host within the prefix, e.g. 5 is the same as "::5".
+        '';
+      };
+      static_ipv4 = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "192.0.2.5";
+        description = ''
+          Fixed IPv4 address of this domain which is sent to the update program with every update instead of the address from the request.
+        '';
+      };
+      static_ipv6 = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "2001:db8::5";
+        description = ''
+          Fixed IPv6 address of this domain which is sent to the update program with every update instead of the address from the request.
+          `ipv6prefixlen` and `ipv6suffix` are ignored if this is set.
+        '';
+      };
+      hooks.post_update = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "systemctl reload nginx" ];
+        description = ''
+          Shell commands to run in the background after the addresses of this domain were updated successfully.
+          The environment variables `DYNDNSD_DOMAIN`, `DYNDNSD_TTL`, `DYNDNSD_IPV4` and `DYNDNSD_IPV6` contain the new values.
+          The address variables are only set if the corresponding address was updated.
         '';
       };
     };
@@ -98,6 +201,10 @@ in
         It also creates the `zonegen` group and allows zonegen to access `/var/lib/bind/zones/dyn/`.
       '';
 
+      strict = lib.mkEnableOption ''
+        refusing to start when the configuration causes warnings, like password hashes which are weaker than the `password_policy` or unknown keys
+      '';
+
       localhost = lib.mkOption {
         type = lib.types.bool;
         default = true;
@@ -109,15 +216,143 @@ in
       };
 
       settings = {
+        audit_log = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          example = "/var/log/dyndnsd/audit.log";
+          description = ''
+            Path of a file to which a line is appended for every successful update of a domain.
+            Each line contains the time, user, client address, domain and the old and new addresses.
+            Old addresses are only known for domains which were updated since dyndnsd was started.
+            The directory needs to be writable by the service, e.g. by adding it to `systemd.services.dyndnsd.serviceConfig.ReadWritePaths`.
+          '';
+        };
+
+        events_file = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          example = "/var/log/dyndnsd/events.ndjson";
+          description = ''
+            Path of a file to which a JSON object is appended as a single line for every update request, whether it succeeded or not.
+            It contains the time, request ID, user, client address, status code and the domains for which the update program was run.
+            The events are written in the background, each line in one piece, and dropped if that falls behind or if the file is a named pipe without a reader.
+            The directory needs to be writable by the service, e.g. by adding it to `systemd.services.dyndnsd.serviceConfig.ReadWritePaths`.
+          '';
+        };
+
+        pepper_file = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          example = "/run/secrets/dyndnsd-pepper";
+          description = ''
+            Path of a file whose contents are used as a secret when hashing and verifying passwords, so the password hashes alone are not enough to brute-force the passwords.
+            Do not put this file into the Nix store.
+            All password hashes need to be generated with `dyndnsd --config <config file> hash-password`, which reads the password from stdin.
+          '';
+        };
+
+        dedupe_window = lib.mkOption {
+          type = lib.types.ints.unsigned;
+          default = 0;
+          example = 5;
+          description = ''
+            Number of seconds during which identical requests of a user only run the update program once.
+            The later requests get the same response as the first one.
+            Many routers send every update twice in quick succession.
+            Set this to 0 to disable it.
+          '';
+        };
+
+        ipv4_source_check = lib.mkOption {
+          type = lib.types.enum [ "off" "warn" "reject" ];
+          default = "off";
+          description = ''
+            What to do when the `ipv4` parameter differs from the IPv4 address the request comes from, which happens when the client is behind another NAT than it thinks or the request is spoofed.
+            `warn` only logs it and `reject` refuses the request.
+            Requests over IPv6 are not checked.
+          '';
+        };
+
+        auth_failure_window = lib.mkOption {
+          type = lib.types.ints.unsigned;
+          default = 0;
+          example = 60;
+          description = ''
+            Number of seconds during which a wrong password sent again from the same address is rejected without verifying it again.
+            This saves the CPU time of hashing it for clients stuck with a wrong password, the attempts are still logged.
+            It is disabled by default, set it to a number of seconds to enable it.
+          '';
+        };
+
+        history_length = lib.mkOption {
+          type = lib.types.ints.unsigned;
+          default = 10;
+          description = ''
+            Number of recent updates per domain which are kept in memory and shown at `/status`.
+            The history is lost when dyndnsd is restarted.
+            Set this to 0 to disable it.
+          '';
+        };
+
+        log_redaction = lib.mkOption {
+          type = lib.types.enum [ "none" "partial" "full" ];
+          default = "none";
+          description = ''
+            How much of the usernames and IP addresses appears in the log.
+            With "partial", only the first character of usernames and the first 24 bits of IPv4 and 48 bits of IPv6 addresses are logged.
+            With "full", they are not logged at all.
+            The audit log is not affected by this.
+          '';
+        };
+
+        log_file = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          example = "/var/log/dyndnsd/dyndnsd.log";
+          description = ''
+            Write the log to this file instead of the journal.
+            dyndnsd opens the file again when it receives SIGUSR1, e.g. from logrotate after renaming it.
+            The directory needs to be writable by the service, e.g. by adding it to `systemd.services.dyndnsd.serviceConfig.ReadWritePaths`.
+          '';
+        };
+
+        web_ui = lib.mkOption {
+          type = lib.types.bool;
+          default = false;
+          description = ''
+            Serve a page at `/` where users log in with their password to see their domains and the addresses last sent to the DNS server, and to update the addresses manually.
+          '';
+        };
+
+        parameter_aliases = lib.mkOption {
+          type = lib.types.attrsOf (lib.types.enum [ "domain" "user" "pass" "ipv4" "ipv6" "dualstack" "ipv6lanprefix" "prefixlen" "verify" "dry_run" "system" "hostname" ]);
+          default = { };
+          example = { ip = "ipv4"; myip6 = "ipv6"; };
+          description = ''
+            Alternative names for the query parameters, for clients which can't be configured to use the usual ones.
+          '';
+        };
+
         listen = {
           ip = lib.mkOption {
-            type = lib.types.str;
+            type = lib.types.nullOr (lib.types.either lib.types.str (lib.types.nonEmptyListOf lib.types.str));
             default = "::1";
-            example = "::";
+            example = [ "0.0.0.0" "::" ];
             description = ''
-              Only listen to incoming requests on a specific IP address.
+              Only listen to incoming requests on a specific IP address or list of IP addresses.
               The default is to listen on IPv6 localhost.
-              The special address :: will listen on all IPv4 and IPv6 addresses.
+              The special address :: will listen on all IPv6 addresses and, depending on `v6only`, also on all IPv4 addresses.
+              Set this to null to only listen on the Unix socket configured with `unix`.
+            '';
+          };
+          v6only = lib.mkOption {
+            type = lib.types.nullOr lib.types.bool;
+            default = null;
+            example = true;
+            description = ''
+              Whether sockets bound to an IPv6 address only accept IPv6 connections (`IPV6_V6ONLY`).
+              Set this to true when listening on both 0.0.0.0 and ::, otherwise the second socket cannot be bound.
+              If this is null, the operating system default is used.
             '';
           };
           port = lib.mkOption {
@@ -127,6 +362,468 @@ in
               The port on which to listen.
             '';
           };
+          unix = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "/run/${RuntimeDirectory}/dyndnsd.sock";
+            description = ''
+              Path of a Unix socket to create and listen on, in addition to the IP addresses.
+              A stale socket left behind at this path is removed on startup and the socket is removed again on shutdown.
+            '';
+          };
+          unix_mode = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "0660";
+            description = ''
+              Permissions of the Unix socket as an octal number.
+              If this is null, the permissions are determined by the umask.
+            '';
+          };
+          unix_group = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "nginx";
+            description = ''
+              Name or ID of the group owning the Unix socket.
+              Together with `unix_mode`, this can be used to only allow the reverse proxy to connect to the socket.
+            '';
+          };
+          proxy_protocol = lib.mkOption {
+            type = lib.types.either lib.types.bool (lib.types.listOf lib.types.bool);
+            default = false;
+            example = [ true false ];
+            description = ''
+              Expect a PROXY protocol (version 1 or 2) header at the start of every connection on the addresses in `ip`.
+              Enable this when dyndnsd is behind a proxy like HAProxy which conveys the real client address this way.
+              Connections without a valid header are closed.
+              Either a single value for all addresses or a list with one value per address in `ip`, e.g. to only expect the header on the address the proxy connects to.
+              The Unix socket is configured separately with `unix_proxy_protocol`.
+            '';
+          };
+          unix_proxy_protocol = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = ''
+              Expect a PROXY protocol header at the start of every connection on the Unix socket configured with `unix`.
+            '';
+          };
+          trusted_proxy_depth = lib.mkOption {
+            type = lib.types.ints.unsigned;
+            default = 0;
+            description = ''
+              Number of reverse proxies in front of dyndnsd whose forwarding header, set with `forwarded_header`, is trusted.
+              The client address is the one the outermost of these proxies got the request from.
+              Requests with fewer hops in the header or an invalid header are rejected with 400 Bad Request.
+              Only enable this if clients cannot reach dyndnsd without going through the proxies, since they could send these headers themselves.
+              Set this to 0 to ignore these headers.
+            '';
+          };
+          forwarded_header = lib.mkOption {
+            type = lib.types.nullOr (lib.types.enum [ "forwarded" "x-forwarded-for" ]);
+            default = null;
+            example = "x-forwarded-for";
+            description = ''
+              The header to which the trusted reverse proxies append the address they got the request from, `Forwarded` (RFC 7239) or `X-Forwarded-For`.
+              Only this header is read, since proxies usually pass the other one on unchanged from the client.
+              This needs to be set if `trusted_proxy_depth` is not 0.
+            '';
+          };
+        };
+
+        limits = {
+          request_timeout = lib.mkOption {
+            type = lib.types.ints.positive;
+            default = 30;
+            description = ''
+              Number of seconds after which an update request is aborted.
+              The update program is killed and the client receives a 504 Gateway Timeout response.
+            '';
+          };
+          max_query_length = lib.mkOption {
+            type = lib.types.ints.unsigned;
+            default = 1024;
+            description = ''
+              Maximum length of the query string in bytes.
+              Longer requests are rejected with 414 URI Too Long.
+            '';
+          };
+          max_header_size = lib.mkOption {
+            type = lib.types.ints.unsigned;
+            default = 8192;
+            description = ''
+              Maximum combined size of all header names and values in bytes.
+              Larger requests are rejected with 431 Request Header Fields Too Large.
+            '';
+          };
+          max_body_size = lib.mkOption {
+            type = lib.types.ints.unsigned;
+            default = 1024;
+            description = ''
+              Maximum size of the request body in bytes, according to the Content-Length header.
+              Larger requests are rejected with 413 Payload Too Large.
+            '';
+          };
+          header_read_timeout = lib.mkOption {
+            type = lib.types.ints.positive;
+            default = 10;
+            description = ''
+              Number of seconds a client has to send the complete header of a request before the connection is closed.
+            '';
+          };
+          idle_timeout = lib.mkOption {
+            type = lib.types.ints.unsigned;
+            default = 60;
+            description = ''
+              Number of seconds after which connections without any traffic or request in progress are closed.
+              Set this to 0 to keep idle connections open.
+            '';
+          };
+          max_connections_per_ip = lib.mkOption {
+            type = lib.types.ints.unsigned;
+            default = 0;
+            description = ''
+              Maximum number of simultaneous connections from a single client address, further connections are closed right away.
+              With `listen.proxy_protocol`, the address from the PROXY protocol header is used.
+              Connections on the Unix socket without the PROXY protocol and connections from the proxies trusted with `listen.trusted_proxy_depth` are not limited, since the address of the client is not known per connection.
+              Behind a proxy which uses neither, all clients share the limit of the proxy, so it becomes a limit on the total number of connections.
+              Set this to 0 to allow any number of connections.
+            '';
+          };
+        };
+
+        verify = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              server = lib.mkOption {
+                type = lib.types.str;
+                example = "[::1]:53";
+                description = ''
+                  Address and port of the authoritative DNS server which is queried to verify an update.
+                '';
+              };
+              timeout = lib.mkOption {
+                type = lib.types.ints.positive;
+                default = 10;
+                description = ''
+                  Number of seconds to wait for the updated records to become visible.
+                  This should be lower than `limits.request_timeout`.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Allow clients to add `verify=1` to the query.
+            The response is then only successful once the DNS server answers with the new addresses.
+          '';
+        };
+
+        password_policy = {
+          min_memory = lib.mkOption {
+            type = lib.types.nullOr lib.types.ints.positive;
+            default = null;
+            example = 19456;
+            description = ''
+              Minimum amount of memory in KiB the argon2 password hashes of the users must use.
+            '';
+          };
+          min_iterations = lib.mkOption {
+            type = lib.types.nullOr lib.types.ints.positive;
+            default = null;
+            example = 2;
+            description = ''
+              Minimum number of iterations the argon2 password hashes of the users must use.
+            '';
+          };
+          on_violation = lib.mkOption {
+            type = lib.types.enum [ "warn" "refuse" ];
+            default = "warn";
+            description = ''
+              Whether to only log a warning or to refuse to start when a password hash is weaker than `min_memory` or `min_iterations`.
+            '';
+          };
+        };
+
+        responses = {
+          success = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "good {ipv4}";
+            description = ''
+              Body of the response to a successful update instead of `ok`.
+              `{domain}` is replaced with the updated domains separated by commas, `{ipv4}` and `{ipv6}` with the new addresses, `{status}` with the HTTP status code and `{message}` with the default body.
+            '';
+          };
+          error = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "{status} {message}";
+            description = ''
+              Body of all other responses.
+              `{status}` is replaced with the HTTP status code and `{message}` with the default body.
+            '';
+          };
+          changes = lib.mkOption {
+            type = lib.types.nullOr (lib.types.enum [ "text" "json" ]);
+            default = null;
+            description = ''
+              Add the previous and the new addresses of the updated domains to the body of successful responses.
+              `text` adds lines like `example.org A 1.2.3.4 -> 1.2.3.5`, `json` turns the body into an object with the usual body as `result` and the addresses as `domains`.
+            '';
+          };
+        };
+
+        features = {
+          ipv4 = lib.mkOption {
+            type = lib.types.bool;
+            default = true;
+            description = ''
+              Whether to handle IPv4 addresses.
+              When disabled, the `ipv4` parameter is rejected and `update_program.ipv4` is not used.
+            '';
+          };
+          ipv6 = lib.mkOption {
+            type = lib.types.bool;
+            default = true;
+            description = ''
+              Whether to handle IPv6 addresses.
+              When disabled, the `ipv6`, `ipv6lanprefix` and `prefixlen` parameters are rejected and `update_program.ipv6` is not used.
+            '';
+          };
+        };
+
+        metrics = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              max_user_labels = lib.mkOption {
+                type = lib.types.ints.unsigned;
+                default = 0;
+                example = 100;
+                description = ''
+                  Number of users which get their own counters, to keep the number of time series bounded on installations with many users.
+                  The counters per user are disabled by default, since `/metrics` does not require authentication and they show the user names and their failed logins.
+                  Only enable them if access to `/metrics` is restricted, e.g. in the reverse proxy.
+                '';
+              };
+              zones = lib.mkOption {
+                type = lib.types.listOf lib.types.str;
+                default = [];
+                example = [ "example.org" "example.com" ];
+                description = ''
+                  Zones for which the runs of the update programs and their durations are counted, with the file name of the program as `backend` label.
+                  A domain belongs to the longest zone containing it, domains outside of all zones are counted as `other`.
+                '';
+              };
+              max_backend_labels = lib.mkOption {
+                type = lib.types.ints.unsigned;
+                default = 100;
+                description = ''
+                  Number of combinations of update program and zone which get their own counters.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Serve counters of the update requests in the Prometheus text format at `/metrics`.
+            The endpoint does not require authentication, so restrict access to it in the reverse proxy if the user names are not supposed to be public.
+          '';
+        };
+
+        checkip = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              rate_limit = lib.mkOption {
+                type = lib.types.ints.unsigned;
+                default = 60;
+                description = ''
+                  Requests per minute and client address, 0 disables the limit.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Answer requests to `/checkip` with the address of the client, so clients can find out their public address without a third-party service.
+            The address is returned as plain text, or as JSON with `?format=json` or `Accept: application/json`.
+          '';
+        };
+
+        geoip = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              database = lib.mkOption {
+                type = lib.types.path;
+                example = "/var/lib/GeoIP/GeoLite2-Country.mmdb";
+                description = ''
+                  MaxMind or GeoLite2 country database, e.g. from `services.geoipupdate`.
+                '';
+              };
+              allowed_countries = lib.mkOption {
+                type = lib.types.listOf lib.types.str;
+                default = [];
+                example = [ "DE" "AT" ];
+                description = ''
+                  ISO 3166-1 codes of the countries update requests are accepted from, all countries are allowed if this is empty.
+                  Users can have their own `allowed_countries`, which replace these.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Restrict the source addresses of update requests to some countries.
+          '';
+        };
+
+        statsd = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              server = lib.mkOption {
+                type = lib.types.str;
+                example = "localhost:8125";
+                description = ''
+                  Host and port of the StatsD server.
+                '';
+              };
+              prefix = lib.mkOption {
+                type = lib.types.str;
+                default = "dyndnsd";
+                description = ''
+                  Prepended to the names of the metrics, separated by a dot.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Push counters and timings of the update requests to a StatsD server over UDP.
+          '';
+        };
+
+        mqtt = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              server = lib.mkOption {
+                type = lib.types.str;
+                example = "localhost:1883";
+                description = ''
+                  Host and port of the MQTT broker.
+                '';
+              };
+              topic = lib.mkOption {
+                type = lib.types.str;
+                default = "dyndnsd/{domain}";
+                description = ''
+                  Topic of the messages, `{domain}` is replaced with the name of the domain.
+                '';
+              };
+              client_id = lib.mkOption {
+                type = lib.types.str;
+                default = "dyndnsd";
+                description = ''
+                  Client identifier sent to the broker.
+                '';
+              };
+              username = lib.mkOption {
+                type = lib.types.nullOr lib.types.str;
+                default = null;
+                description = ''
+                  Username for the broker.
+                '';
+              };
+              password_file = lib.mkOption {
+                type = lib.types.nullOr lib.types.str;
+                default = null;
+                description = ''
+                  File containing the password for the broker.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Publish a retained JSON message with the domain, TTL and current addresses to an MQTT broker whenever the addresses of a domain change.
+          '';
+        };
+
+        notify = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              servers = lib.mkOption {
+                type = lib.types.listOf lib.types.str;
+                example = [ "[2001:db8::2]:53" ];
+                description = ''
+                  Addresses and ports of the secondary servers.
+                '';
+              };
+              zones = lib.mkOption {
+                type = lib.types.listOf lib.types.str;
+                example = [ "example.org" ];
+                description = ''
+                  Zones for which a NOTIFY is sent when a domain in them was updated.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Send DNS NOTIFY messages to secondary servers after successful updates, so they transfer the zone right away.
+            This is only needed if the update program does not already cause the primary server to send them, e.g. when it writes zone files.
+          '';
+        };
+
+        cors = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              allowed_origins = lib.mkOption {
+                type = lib.types.listOf lib.types.str;
+                example = [ "https://dashboard.example.org" ];
+                description = ''
+                  Origins of the web pages which may call dyndnsd, like `https://dashboard.example.org`.
+                  `"*"` allows any origin.
+                '';
+              };
+              allowed_methods = lib.mkOption {
+                type = lib.types.listOf lib.types.str;
+                default = [ "GET" ];
+                description = ''
+                  HTTP methods which the web pages may use.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Send CORS headers so that single-page web applications can call dyndnsd directly from the browser.
+            Requests from other origins are rejected.
+          '';
+        };
+
+        circuit_breaker = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule {
+            options = {
+              failures = lib.mkOption {
+                type = lib.types.ints.positive;
+                default = 5;
+                description = ''
+                  Number of consecutive failures of the update program after which it is not run anymore for a while.
+                '';
+              };
+              cooldown = lib.mkOption {
+                type = lib.types.ints.unsigned;
+                default = 60;
+                description = ''
+                  Number of seconds during which requests are answered with 503 Service Unavailable instead of running the update program.
+                '';
+              };
+            };
+          });
+          default = null;
+          description = ''
+            Stop running an update program which keeps failing for a while instead of running it for every request.
+          '';
         };
 
         update_program = {
@@ -144,6 +841,65 @@ in
             example = [ "-k" "/etc/bind/ddns.key" ];
             description = ''
               Command line arguments the update program will be called with.
+              If any of them contains `{domain}`, `{ttl}`, `{ipv4}` or `{ipv6}`, the update program is run once for every domain and the placeholders are replaced with its values.
+            '';
+          };
+          env = lib.mkOption {
+            type = lib.types.attrsOf lib.types.str;
+            default = {};
+            example = { DOMAIN = "{domain}"; IPV4 = "{ipv4}"; };
+            description = ''
+              Environment variables of the update program.
+              They can contain the same placeholders as `args`, with the same effect.
+            '';
+          };
+          clear_env = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = ''
+              Start the update program with an empty environment instead of the one of dyndnsd, which may contain secrets.
+              Only `env`, the proxy variables and the variables in `env_allowlist` are set.
+            '';
+          };
+          env_allowlist = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [];
+            example = [ "PATH" ];
+            description = ''
+              Variables of the environment of dyndnsd which are passed on to the update program if `clear_env` is set.
+            '';
+          };
+          working_directory = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            description = ''
+              Working directory of the update program, the one of dyndnsd if this is not set.
+            '';
+          };
+          mode = lib.mkOption {
+            type = lib.types.nullOr (lib.types.enum [ "batch" "per-domain" ]);
+            default = null;
+            description = ''
+              Whether the update program is run once for all domains of a request or once for each domain.
+              In per-domain mode, the domains for which the update program succeeded are updated even if it failed for others.
+              If this is not set, it is per-domain if `args` or `env` contain placeholders and batch otherwise.
+            '';
+          };
+          parallelism = lib.mkOption {
+            type = lib.types.nullOr lib.types.ints.positive;
+            default = null;
+            description = ''
+              How many instances of the update program may run at the same time for one request in per-domain mode, 1 if this is not set.
+            '';
+          };
+          proxy = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "socks5h://[::1]:1080";
+            description = ''
+              Proxy URL for update programs which talk to an HTTP API.
+              It is passed to the update program in the `http_proxy`, `https_proxy`, `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment variables.
+              If this is not set, the update program inherits these variables from the environment of dyndnsd.
             '';
           };
           initial_stdin = lib.mkOption {
@@ -182,6 +938,21 @@ in
                 The three different variables are replaced with the appropriate values before the string is sent to the update program.
               '';
             };
+            bin = lib.mkOption {
+              type = lib.types.nullOr lib.types.path;
+              default = null;
+              description = ''
+                Program for the IPv4 updates, if they should not go to the same program as the other updates.
+                If only `args` is set, this is the same as `update_program.bin`.
+              '';
+            };
+            args = lib.mkOption {
+              type = lib.types.nullOr (lib.types.listOf lib.types.str);
+              default = null;
+              description = ''
+                Command line arguments of the program for the IPv4 updates, the same as `update_program.args` if this is not set.
+              '';
+            };
           };
           ipv6 = {
             stdin = lib.mkOption {
@@ -193,9 +964,45 @@ in
                 The three different variables are replaced with the appropriate values before the string is sent to the update program.
               '';
             };
+            bin = lib.mkOption {
+              type = lib.types.nullOr lib.types.path;
+              default = null;
+              description = ''
+                Program for the IPv6 updates, if they should not go to the same program as the other updates.
+                If only `args` is set, this is the same as `update_program.bin`.
+              '';
+            };
+            args = lib.mkOption {
+              type = lib.types.nullOr (lib.types.listOf lib.types.str);
+              default = null;
+              description = ''
+                Command line arguments of the program for the IPv6 updates, the same as `update_program.args` if this is not set.
+              '';
+            };
           };
         };
 
+        shadow_program = lib.mkOption {
+          type = lib.types.nullOr settingsFormat.type;
+          default = null;
+          example = lib.literalExpression ''
+            {
+              bin = "/usr/local/bin/update-via-api";
+              args = [ ];
+              stdin_per_zone_update = "";
+              final_stdin = "";
+              ipv4.stdin = "A {domain} {ttl} {ipv4}\n";
+              ipv6.stdin = "AAAA {domain} {ttl} {ipv6}\n";
+            }
+          '';
+          description = ''
+            A second update program with the same options as `update_program` which gets the same updates at the same time.
+            Its result is only logged and compared with the one of `update_program`, which is useful while migrating to another backend.
+            It runs in the background, so the responses do not wait for it.
+            It is killed after `limits.request_timeout` and skipped while 16 runs of it are still going on.
+          '';
+        };
+
         users = lib.mkOption {
           type = lib.types.attrsOf (lib.types.submodule userOpts);
           default = {};
@@ -250,7 +1057,7 @@ in
           inherit RuntimeDirectory;
           EnvironmentFile = cfg.environmentFiles;
           ExecStartPre = lib.mkIf (cfg.environmentFiles != []) [ "'${pkgs.envsubst}/bin/envsubst' -no-unset -i '${settingsFile}' -o '${runtimeConfigPath}'" ];
-          ExecStart = [ "" "${pkgs.dyndnsd}/bin/dyndnsd --config '${runtimeConfigPath}'" ];
+          ExecStart = [ "" "${pkgs.dyndnsd}/bin/dyndnsd --config '${runtimeConfigPath}'${lib.optionalString cfg.strict " --strict"}" ];
         } // lib.optionalAttrs cfg.localhost {
           IPAddressAllow = [ "localhost" ];
           IPAddressDeny = "any";
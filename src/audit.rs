@@ -0,0 +1,59 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::process::DomainUpdate;
+use crate::server::ClientAddr;
+use crate::state::Addresses;
+use log::error;
+use std::fmt::{Display, Write as _};
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+fn change<T: Display>(old: Option<T>, new: T) -> String {
+	old.map_or_else(|| format!("unknown->{new}"), |old| format!("{old}->{new}"))
+}
+
+/// Append one line per updated domain to the audit log.
+/// Errors are only logged since the update itself already succeeded.
+pub async fn write(
+	path: &Path,
+	user: &str,
+	client: ClientAddr,
+	changes: &[(&DomainUpdate<'_>, Addresses)],
+) {
+	let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+	let client = client
+		.0
+		.map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+	let mut lines = String::new();
+	for (update, old) in changes {
+		let _ = write!(
+			lines,
+			"{timestamp} user={user} client={client} domain={}",
+			update.domain
+		);
+		if let Some(ipv4) = update.ipv4 {
+			let _ = write!(lines, " ipv4={}", change(old.ipv4, ipv4));
+		}
+		if let Some(ipv6) = update.ipv6 {
+			let _ = write!(lines, " ipv6={}", change(old.ipv6, ipv6));
+		}
+		lines.push('\n');
+	}
+
+	// Open the file again for every write so that the file can be rotated without restarting
+	let result = async {
+		let mut file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.await?;
+		file.write_all(lines.as_bytes()).await
+	}
+	.await;
+	if let Err(e) = result {
+		error!("Error writing to audit log `{}`: {e}", path.display());
+	}
+}
@@ -0,0 +1,55 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Routers with a wrong password keep sending it, remember the failures instead of running argon2 for every attempt
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A failed attempt, the password is only kept as a hash with a random key
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+	ip: Option<IpAddr>,
+	user: String,
+	pass: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct AuthFailures {
+	entries: Mutex<HashMap<Key, Instant>>,
+	hasher: RandomState,
+}
+
+impl AuthFailures {
+	fn key(&self, ip: Option<IpAddr>, user: &str, pass: &str) -> Key {
+		Key {
+			ip,
+			user: user.to_string(),
+			pass: self.hasher.hash_one(pass),
+		}
+	}
+
+	/// Whether the same attempt failed within the last `window`
+	pub fn contains(&self, ip: Option<IpAddr>, user: &str, pass: &str, window: Duration) -> bool {
+		let key = self.key(ip, user, pass);
+		let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		let failed = entries
+			.get(&key)
+			.is_some_and(|failed| failed.elapsed() < window);
+		drop(entries);
+		failed
+	}
+
+	/// Remember a failed attempt and forget the ones older than `window`
+	pub fn insert(&self, ip: Option<IpAddr>, user: &str, pass: &str, window: Duration) {
+		let key = self.key(ip, user, pass);
+		let now = Instant::now();
+		let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		entries.retain(|_, failed| now.duration_since(*failed) < window);
+		entries.insert(key, now);
+	}
+}
@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Tasks which continue in the background after the response was sent, like the shadow program and the post-update hooks
+
+use std::future::Future;
+use std::mem;
+use std::sync::{Mutex, PoisonError};
+use tokio::task::JoinSet;
+
+#[derive(Debug, Default)]
+pub struct Tasks {
+	tasks: Mutex<JoinSet<()>>,
+}
+
+impl Tasks {
+	pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+		let mut tasks = self.tasks.lock().unwrap_or_else(PoisonError::into_inner);
+		// Forget the tasks which already finished, so the set does not grow forever
+		while tasks.try_join_next().is_some() {}
+		tasks.spawn(task);
+	}
+
+	/// Like `spawn()`, but only if fewer than `limit` tasks are still running. Returns whether the task was spawned.
+	pub fn try_spawn(&self, task: impl Future<Output = ()> + Send + 'static, limit: usize) -> bool {
+		let mut tasks = self.tasks.lock().unwrap_or_else(PoisonError::into_inner);
+		while tasks.try_join_next().is_some() {}
+		if tasks.len() >= limit {
+			return false;
+		}
+		tasks.spawn(task);
+		true
+	}
+
+	/// Wait until all tasks finished, so the subcommands do not exit before them
+	pub async fn wait(&self) {
+		let mut tasks = mem::take(&mut *self.tasks.lock().unwrap_or_else(PoisonError::into_inner));
+		while tasks.join_next().await.is_some() {}
+	}
+}
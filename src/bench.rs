@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Load test of a running instance with synthetic update requests
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use warp::hyper::{client::HttpConnector, Client, Uri};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+	/// Base URL of the instance, e.g. `http://[::1]:9841`
+	#[arg(long)]
+	url: String,
+	/// User to send the requests as
+	#[arg(long)]
+	user: String,
+	/// Password of the user
+	#[arg(long)]
+	pass: String,
+	/// Total number of requests
+	#[arg(long, default_value_t = 100)]
+	requests: usize,
+	/// Number of requests in flight at the same time
+	#[arg(long, default_value_t = 10)]
+	concurrency: usize,
+}
+
+/// Latency and status code of every request sent by one worker
+type Results = Vec<(Duration, Result<u16, String>)>;
+
+fn request_uri(args: &Args, i: usize) -> Result<Uri> {
+	// Different addresses for every request, so identical requests are not deduplicated by the server
+	let i = u32::try_from(i)?;
+	let query = serde_urlencoded::to_string([
+		("user", args.user.as_str()),
+		("pass", args.pass.as_str()),
+		(
+			"ipv4",
+			&Ipv4Addr::from(0xc000_0200 | (i & 0xff)).to_string(),
+		),
+		(
+			"ipv6",
+			&Ipv6Addr::from(0x2001_0db8 << 96 | u128::from(i)).to_string(),
+		),
+		("dry_run", "1"),
+	])?;
+	let uri = format!("{}/update?{query}", args.url.trim_end_matches('/'));
+	uri.parse()
+		.wrap_err_with(|| format!("Invalid URL `{}`", args.url))
+}
+
+async fn worker(
+	client: Client<HttpConnector>,
+	uris: Arc<Vec<Uri>>,
+	next: Arc<AtomicUsize>,
+) -> Results {
+	let mut results = Vec::new();
+	while let Some(uri) = uris.get(next.fetch_add(1, Ordering::Relaxed)) {
+		let start = Instant::now();
+		let status = client
+			.get(uri.clone())
+			.await
+			.map(|response| response.status().as_u16())
+			.map_err(|e| e.to_string());
+		results.push((start.elapsed(), status));
+	}
+	results
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+	let index = (sorted.len() * percent).div_ceil(100).saturating_sub(1);
+	sorted[index.min(sorted.len() - 1)]
+}
+
+pub async fn run(args: &Args) -> Result<()> {
+	if args.requests == 0 || args.concurrency == 0 {
+		return Err(eyre!(
+			"The number of requests and the concurrency must not be zero"
+		));
+	}
+	let uris = (0..args.requests)
+		.map(|i| request_uri(args, i))
+		.collect::<Result<Vec<_>>>()?;
+	let uris = Arc::new(uris);
+	let next = Arc::new(AtomicUsize::new(0));
+	let client = Client::new();
+
+	let start = Instant::now();
+	let mut workers = JoinSet::new();
+	for _ in 0..args.concurrency {
+		workers.spawn(worker(client.clone(), Arc::clone(&uris), Arc::clone(&next)));
+	}
+	let mut results = Vec::with_capacity(args.requests);
+	while let Some(worker_results) = workers.join_next().await {
+		results.extend(worker_results?);
+	}
+	let elapsed = start.elapsed();
+
+	let mut latencies: Vec<Duration> = results.iter().map(|(latency, _)| *latency).collect();
+	latencies.sort_unstable();
+	let mut outcomes = BTreeMap::new();
+	for (_, status) in results {
+		let outcome = status.map_or_else(|e| format!("error: {e}"), |status| status.to_string());
+		*outcomes.entry(outcome).or_insert(0) += 1;
+	}
+
+	let mut stdout = std::io::stdout().lock();
+	#[allow(clippy::cast_precision_loss)]
+	let rate = args.requests as f64 / elapsed.as_secs_f64();
+	writeln!(
+		stdout,
+		"{} requests in {:.2} s ({rate:.1} requests/s) with {} in flight",
+		args.requests,
+		elapsed.as_secs_f64(),
+		args.concurrency
+	)?;
+	for (outcome, count) in outcomes {
+		writeln!(stdout, "{outcome}: {count}")?;
+	}
+	for percent in [50, 90, 99, 100] {
+		writeln!(
+			stdout,
+			"p{percent}: {:.1} ms",
+			percentile(&latencies, percent).as_secs_f64() * 1000.0
+		)?;
+	}
+	Ok(())
+}
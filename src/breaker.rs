@@ -0,0 +1,87 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CircuitBreaker;
+use log::{error, warn};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct Inner {
+	consecutive_failures: u32,
+	open_until: Option<Instant>,
+}
+
+/// Keeps track of consecutive failures of the update program
+#[derive(Debug, Default)]
+pub struct Breaker {
+	inner: Mutex<Inner>,
+}
+
+impl Breaker {
+	/// Start a run of the update program, which counts as a failure unless it finishes successfully
+	pub const fn start<'a>(&'a self, config: &'a CircuitBreaker) -> Run<'a> {
+		Run {
+			breaker: self,
+			config,
+			finished: false,
+		}
+	}
+
+	/// How long to wait before the update program is run again, if the breaker is open
+	pub fn retry_after(&self) -> Option<Duration> {
+		let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		inner
+			.open_until
+			.and_then(|open_until| open_until.checked_duration_since(Instant::now()))
+	}
+
+	pub fn record_success(&self) {
+		let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		*inner = Inner::default();
+	}
+
+	/// Open the breaker for the cool-down period once too many failures happened in a row.
+	/// After the cool-down period, a single failure is enough to open it again.
+	pub fn record_failure(&self, config: &CircuitBreaker) {
+		let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+		if inner.consecutive_failures >= config.failures {
+			inner.open_until = Some(Instant::now() + config.cooldown);
+			let failures = inner.consecutive_failures;
+			drop(inner);
+			error!(
+				"Circuit breaker opened: the update program failed {failures} times in a row, not running it for {:?}",
+				config.cooldown
+			);
+		}
+	}
+}
+
+/// A run of the update program.
+/// If it is dropped before it finished, e.g. because the request timed out, it counts as a failure.
+pub struct Run<'a> {
+	breaker: &'a Breaker,
+	config: &'a CircuitBreaker,
+	finished: bool,
+}
+
+impl Run<'_> {
+	pub fn finish(mut self, success: bool) {
+		self.finished = true;
+		if success {
+			self.breaker.record_success();
+		} else {
+			self.breaker.record_failure(self.config);
+		}
+	}
+}
+
+impl Drop for Run<'_> {
+	fn drop(&mut self) {
+		if !self.finished {
+			warn!("The update program did not finish, counting it as a failure for the circuit breaker");
+			self.breaker.record_failure(self.config);
+		}
+	}
+}
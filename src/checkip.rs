@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Tells clients their public address, so they do not need a third-party service for that
+
+use crate::config::CheckIp;
+use crate::rate_limit::RateLimiter;
+use crate::server::ClientAddr;
+use log::warn;
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+use warp::{http::StatusCode, reply::Response, Reply};
+
+/// Length of the window of the rate limit in seconds
+const WINDOW_SECS: u64 = 60;
+
+/// Answer with the address of the client, as JSON if `json` is set
+pub fn reply(
+	checkip: &CheckIp,
+	limiter: &RateLimiter<IpAddr>,
+	client: ClientAddr,
+	json: bool,
+) -> Response {
+	let Some(ip) = client.0.map(|addr| addr.ip().to_canonical()) else {
+		return warp::reply::with_status("Unknown address", StatusCode::INTERNAL_SERVER_ERROR)
+			.into_response();
+	};
+	if let Some(retry_after) =
+		limiter.check(ip, checkip.rate_limit, Duration::from_secs(WINDOW_SECS))
+	{
+		warn!("Too many requests to /checkip from {client}");
+		return warp::reply::with_header(
+			warp::reply::with_status("Too many requests", StatusCode::TOO_MANY_REQUESTS),
+			"Retry-After",
+			(retry_after.as_secs() + 1).to_string(),
+		)
+		.into_response();
+	}
+	if json {
+		warp::reply::json(&json!({ "ip": ip })).into_response()
+	} else {
+		format!("{ip}\n").into_response()
+	}
+}
@@ -1,76 +1,1078 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use argon2::password_hash::PasswordHash;
+use crate::logging::Redaction;
+use crate::process::PARAMETER_NAMES;
+use argon2::{
+	password_hash::{PasswordHash, PasswordHashString},
+	Params,
+};
 use color_eyre::eyre::{eyre, Result, WrapErr};
+use log::warn;
+use nix::unistd::{Gid, Group};
+use serde::Deserializer;
 use serde_derive::Deserialize;
 use std::collections::HashMap;
 use std::fs;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::{Method, Uri};
 
 #[derive(Debug, Deserialize)]
 struct RawConfig {
 	listen: RawListen,
+	#[serde(default)]
+	limits: RawLimits,
+	audit_log: Option<PathBuf>,
+	events_file: Option<PathBuf>,
+	pepper_file: Option<PathBuf>,
+	dedupe_window: Option<u64>,
+	auth_failure_window: Option<u64>,
+	history_length: Option<usize>,
+	#[serde(default)]
+	ipv4_source_check: SourceCheck,
+	#[serde(default)]
+	log_redaction: Redaction,
+	log_file: Option<PathBuf>,
+	verify: Option<RawVerify>,
+	circuit_breaker: Option<RawCircuitBreaker>,
+	metrics: Option<RawMetrics>,
+	checkip: Option<RawCheckIp>,
+	geoip: Option<RawGeoIp>,
+	statsd: Option<RawStatsd>,
+	cors: Option<RawCors>,
+	mqtt: Option<RawMqtt>,
+	notify: Option<Notify>,
+	#[serde(default)]
+	web_ui: bool,
+	#[serde(default)]
+	parameter_aliases: HashMap<String, String>,
+	#[serde(default)]
+	password_policy: RawPasswordPolicy,
+	#[serde(default)]
+	responses: Responses,
+	#[serde(default)]
+	features: Features,
 	update_program: UpdateProgram,
+	shadow_program: Option<UpdateProgram>,
 	users: HashMap<String, RawUser>,
 }
 
 #[derive(Debug, Deserialize)]
 struct RawListen {
-	ip: IpAddr,
-	port: u16,
+	ip: Option<RawIps>,
+	port: Option<u16>,
+	v6only: Option<bool>,
+	unix: Option<PathBuf>,
+	unix_mode: Option<String>,
+	unix_group: Option<String>,
+	proxy_protocol: Option<RawProxyProtocol>,
+	#[serde(default)]
+	unix_proxy_protocol: bool,
+	trusted_proxy_depth: Option<usize>,
+	forwarded_header: Option<ForwardedHeader>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawIps {
+	One(IpAddr),
+	Many(Vec<IpAddr>),
+}
+
+/// One setting for all addresses in `listen.ip` or one per address
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawProxyProtocol {
+	All(bool),
+	PerAddress(Vec<bool>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RawLimits {
+	request_timeout: Option<u64>,
+	max_query_length: Option<usize>,
+	max_header_size: Option<usize>,
+	max_body_size: Option<u64>,
+	header_read_timeout: Option<u64>,
+	idle_timeout: Option<u64>,
+	max_connections_per_ip: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawVerify {
+	server: SocketAddr,
+	timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCircuitBreaker {
+	failures: Option<u32>,
+	cooldown: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawMetrics {
+	max_user_labels: Option<usize>,
+	#[serde(default)]
+	zones: Vec<String>,
+	max_backend_labels: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCheckIp {
+	rate_limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawGeoIp {
+	database: PathBuf,
+	#[serde(default)]
+	allowed_countries: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawStatsd {
+	server: String,
+	prefix: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawMqtt {
+	server: String,
+	topic: Option<String>,
+	client_id: Option<String>,
+	username: Option<String>,
+	password_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCors {
+	allowed_origins: Vec<String>,
+	allowed_methods: Option<Vec<String>>,
+}
+
+/// Lower bounds for the argon2 parameters of the password hashes
+#[derive(Debug, Default, Deserialize)]
+struct RawPasswordPolicy {
+	/// In KiB
+	min_memory: Option<u32>,
+	min_iterations: Option<u32>,
+	#[serde(default)]
+	on_violation: PolicyViolation,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PolicyViolation {
+	#[default]
+	Warn,
+	Refuse,
 }
 
 #[derive(Debug, Deserialize)]
 struct RawUser {
 	hash: String,
 	domains: HashMap<String, Domain>,
+	allow_ipv4: Option<bool>,
+	allow_ipv6: Option<bool>,
+	allow_ipv6lanprefix: Option<bool>,
+	#[serde(default)]
+	role: Role,
+	site: Option<String>,
+	#[serde(default)]
+	quota: Quota,
+	allowed_countries: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug)]
-pub struct Config<'a> {
-	pub listen: SocketAddr,
+pub struct Config {
+	pub listen: Listen,
+	pub limits: Limits,
+	pub audit_log: Option<PathBuf>,
+	/// One JSON object is appended to this file for every update request
+	pub events_file: Option<PathBuf>,
+	/// Secret mixed into the password hashes, kept out of the config file
+	pub pepper: Option<Vec<u8>>,
+	/// Identical requests within this time only run the update program once, zero disables this
+	pub dedupe_window: Duration,
+	/// A wrong password is rejected without verifying it again if it was sent from the same address within this time, zero disables this
+	pub auth_failure_window: Duration,
+	/// Number of recent updates per domain shown at `/status`
+	pub history_length: usize,
+	pub ipv4_source_check: SourceCheck,
+	pub log_redaction: Redaction,
+	/// Write the log to this file instead of stderr
+	pub log_file: Option<PathBuf>,
+	pub verify: Option<Verify>,
+	pub circuit_breaker: Option<CircuitBreaker>,
+	pub metrics: Option<Metrics>,
+	pub checkip: Option<CheckIp>,
+	pub geoip: Option<GeoIp>,
+	pub statsd: Option<Statsd>,
+	pub cors: Option<Cors>,
+	pub mqtt: Option<Mqtt>,
+	pub notify: Option<Notify>,
+	/// Serve the HTML page for manual updates at `/`
+	pub web_ui: bool,
+	/// Alternative names of query parameters, mapped to the names of `QueryParameters`
+	pub parameter_aliases: HashMap<String, String>,
+	pub responses: Responses,
+	pub features: Features,
 	pub update_program: UpdateProgram,
-	pub users: HashMap<String, User<'a>>,
+	/// Gets the same updates as `update_program`, only to compare the results while migrating to another backend
+	pub shadow_program: Option<UpdateProgram>,
+	pub users: HashMap<String, User>,
+	/// Problems found while loading the config, they were already logged
+	pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Listen {
+	pub addrs: Vec<ListenAddr>,
+	pub v6only: Option<bool>,
+	pub unix: Option<UnixSocket>,
+	/// `None` ignores the forwarding headers
+	pub trusted_proxies: Option<TrustedProxies>,
+}
+
+/// The header to which the trusted reverse proxies append the address they got the request from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+	/// RFC 7239
+	Forwarded,
+	XForwardedFor,
+}
+
+/// Reverse proxies in front of dyndnsd whose forwarding header is trusted
+#[derive(Clone, Copy, Debug)]
+pub struct TrustedProxies {
+	pub header: ForwardedHeader,
+	/// Number of proxies, the client address is the one the outermost of them got the request from
+	pub depth: usize,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct ListenAddr {
+	pub addr: SocketAddr,
+	/// Whether every connection starts with a PROXY protocol header
+	pub proxy_protocol: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct UnixSocket {
+	pub path: PathBuf,
+	pub mode: Option<u32>,
+	pub group: Option<Gid>,
+	pub proxy_protocol: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+	pub request_timeout: Duration,
+	pub max_query_length: usize,
+	pub max_header_size: usize,
+	pub max_body_size: u64,
+	/// Time a client has to send the complete header of a request
+	pub header_read_timeout: Duration,
+	/// Close connections without any traffic or request in progress after this long, zero disables this
+	pub idle_timeout: Duration,
+	/// Zero allows any number of connections
+	pub max_connections_per_ip: usize,
+}
+
+/// Where and how long to wait for updates to become visible when a client asks for verification
+#[derive(Clone, Debug)]
+pub struct Verify {
+	pub server: SocketAddr,
+	pub timeout: Duration,
+}
+
+/// After how many consecutive failures the update program is not run anymore and for how long
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+	pub failures: u32,
+	pub cooldown: Duration,
+}
+
+/// Settings of the `/metrics` endpoint, which only exists if this is configured
+#[derive(Clone, Debug)]
+pub struct Metrics {
+	/// Number of users with their own counters, to keep the number of time series bounded. 0 by default, since `/metrics` is public.
+	pub max_user_labels: usize,
+	/// Zones the runs of the update programs are counted for, by the longest one containing the domain
+	pub zones: Vec<String>,
+	/// Number of combinations of update program and zone with their own counters
+	pub max_backend_labels: usize,
+}
+
+impl Metrics {
+	/// The configured zone containing `domain`, or `other`
+	#[must_use]
+	pub fn zone(&self, domain: &str) -> &str {
+		self.zones
+			.iter()
+			.filter(|zone| {
+				domain
+					.strip_suffix(zone.as_str())
+					.is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+			})
+			.max_by_key(|zone| zone.len())
+			.map_or("other", String::as_str)
+	}
+}
+
+/// Settings of the `/checkip` endpoint, which only exists if this is configured
+#[derive(Clone, Debug)]
+pub struct CheckIp {
+	/// Requests per minute and client address, zero disables the limit
+	pub rate_limit: u32,
+}
+
+/// Restricts the source addresses of update requests to some countries
+#[derive(Clone, Debug)]
+pub struct GeoIp {
+	pub reader: Arc<maxminddb::Reader<Vec<u8>>>,
+	/// Upper case ISO 3166-1 codes, all countries are allowed if this is empty
+	pub allowed_countries: Vec<String>,
+}
+
+/// Where to push counters and timings of the update requests
+#[derive(Clone, Debug)]
+pub struct Statsd {
+	/// Host and port
+	pub server: String,
+	/// Prepended to the names of the metrics, separated by a dot
+	pub prefix: String,
+}
+
+/// Where to publish the addresses of domains when they change
+#[derive(Clone, Debug)]
+pub struct Mqtt {
+	/// Host and port
+	pub server: String,
+	/// `{domain}` is replaced with the name of the domain
+	pub topic: String,
+	pub client_id: String,
+	pub username: Option<String>,
+	pub password: Option<Vec<u8>>,
+}
+
+/// Secondary servers which are told about changes of the zones
+#[derive(Clone, Debug, Deserialize)]
+pub struct Notify {
+	pub servers: Vec<SocketAddr>,
+	/// Updates of domains in these zones (or of the zones themselves) trigger a NOTIFY
+	pub zones: Vec<String>,
+}
+
+/// Which browser origins may call the endpoints
+#[derive(Clone, Debug)]
+pub struct Cors {
+	/// `None` allows any origin
+	pub allowed_origins: Option<Vec<String>>,
+	pub allowed_methods: Vec<Method>,
+}
+
+/// Address families handled by dyndnsd, for deployments which only use one of them
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct Features {
+	pub ipv4: bool,
+	pub ipv6: bool,
+}
+
+impl Default for Features {
+	fn default() -> Self {
+		Self {
+			ipv4: true,
+			ipv6: true,
+		}
+	}
+}
+
+/// Templates replacing the default response bodies, for clients which look for a specific phrase
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Responses {
+	pub success: Option<String>,
+	pub error: Option<String>,
+	/// Add the previous and the new addresses of the updated domains to the body of successful responses
+	pub changes: Option<ChangesFormat>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangesFormat {
+	/// One line per address after the usual body, e.g. `example.org A 1.2.3.4 -> 1.2.3.5`
+	Text,
+	/// The usual body as `result` and the addresses as `domains` of a JSON object
+	Json,
+}
+
+/// Replaced with the values of a domain update in the templates of the update program
+pub const PLACEHOLDERS: [&str; 4] = ["{domain}", "{ttl}", "{ipv4}", "{ipv6}"];
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct UpdateProgram {
 	pub bin: String,
 	pub args: Vec<String>,
+	/// Environment variables of the update program
+	#[serde(default)]
+	pub env: HashMap<String, String>,
+	/// Start the update program with an empty environment, except for `env`, `env_allowlist` and the proxy
+	#[serde(default)]
+	pub clear_env: bool,
+	/// Variables of the environment of dyndnsd which are passed on if `clear_env` is set
+	#[serde(default)]
+	pub env_allowlist: Vec<String>,
+	/// Working directory of the update program, the one of dyndnsd if this is not set
+	pub working_directory: Option<PathBuf>,
+	/// Defaults to `PerDomain` if the arguments or the environment contain placeholders, otherwise to `Batch`
+	pub mode: Option<UpdateMode>,
+	/// How many instances of the update program may run at the same time for one request in `PerDomain` mode
+	pub parallelism: Option<NonZeroUsize>,
+	pub proxy: Option<String>,
 	pub initial_stdin: Option<String>,
 	pub stdin_per_zone_update: String,
 	pub final_stdin: String,
-	pub ipv4: SpecialUpdateProgram,
-	pub ipv6: SpecialUpdateProgram,
+	/// Only optional if the address family is disabled in `[features]`
+	pub ipv4: Option<SpecialUpdateProgram>,
+	pub ipv6: Option<SpecialUpdateProgram>,
+}
+
+/// Whether the update program is run once for all domains of a request or once for each of them
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateMode {
+	Batch,
+	/// Domains whose update program succeeded are updated even if it failed for others
+	PerDomain,
+}
+
+impl UpdateProgram {
+	/// Whether the arguments or the environment refer to a single domain
+	fn has_placeholders(&self) -> bool {
+		self.args.iter().chain(self.env.values()).any(|template| {
+			PLACEHOLDERS
+				.iter()
+				.any(|placeholder| template.contains(placeholder))
+		})
+	}
+
+	#[must_use]
+	pub fn mode(&self) -> UpdateMode {
+		self.mode.unwrap_or_else(|| {
+			if self.has_placeholders() {
+				UpdateMode::PerDomain
+			} else {
+				UpdateMode::Batch
+			}
+		})
+	}
+
+	/// The program for the updates of one address family, if it differs from the main one
+	#[must_use]
+	pub fn for_family(&self, family: Option<&SpecialUpdateProgram>) -> Option<Self> {
+		let family = family?;
+		if family.bin.is_none() && family.args.is_none() {
+			return None;
+		}
+		Some(Self {
+			bin: family.bin.clone().unwrap_or_else(|| self.bin.clone()),
+			args: family.args.clone().unwrap_or_else(|| self.args.clone()),
+			..self.clone()
+		})
+	}
+
+	#[must_use]
+	pub fn parallelism(&self) -> usize {
+		self.parallelism.map_or(1, NonZeroUsize::get)
+	}
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SpecialUpdateProgram {
 	pub stdin: String,
+	/// Updates of this address family are sent to a different program if one of these is set
+	pub bin: Option<String>,
+	pub args: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Domain {
 	pub ttl: u32,
-	pub ipv6prefixlen: u8,
+	pub ipv6prefixlen: Ipv6PrefixLen,
+	#[serde(deserialize_with = "deserialize_ipv6suffix")]
 	pub ipv6suffix: Ipv6Addr,
+	/// Always sent to the update program instead of the address from the request
+	pub static_ipv4: Option<Ipv4Addr>,
+	pub static_ipv6: Option<Ipv6Addr>,
+	#[serde(default)]
+	pub hooks: Hooks,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Hooks {
+	/// Shell commands to run after the addresses of the domain were updated successfully
+	#[serde(default)]
+	pub post_update: Vec<String>,
+}
+
+/// The suffix is either written as an IPv6 address or as the number of the host within the prefix
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawIpv6Suffix {
+	Address(Ipv6Addr),
+	Host(u64),
+}
+
+fn deserialize_ipv6suffix<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Ipv6Addr, D::Error> {
+	Ok(
+		match <RawIpv6Suffix as serde::Deserialize>::deserialize(deserializer)? {
+			RawIpv6Suffix::Address(address) => address,
+			RawIpv6Suffix::Host(host) => Ipv6Addr::from(u128::from(host)),
+		},
+	)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawIpv6PrefixLen {
+	Fixed(u8),
+	Keyword(String),
+}
+
+/// Either a fixed prefix length or `lan` to use the LAN prefix sent by the router
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "RawIpv6PrefixLen")]
+pub enum Ipv6PrefixLen {
+	Fixed(u8),
+	Lan,
+}
+
+impl TryFrom<RawIpv6PrefixLen> for Ipv6PrefixLen {
+	type Error = String;
+
+	fn try_from(raw: RawIpv6PrefixLen) -> Result<Self, Self::Error> {
+		match raw {
+			RawIpv6PrefixLen::Fixed(prefixlen) => Ok(Self::Fixed(prefixlen)),
+			RawIpv6PrefixLen::Keyword(keyword) if keyword == "lan" => Ok(Self::Lan),
+			RawIpv6PrefixLen::Keyword(keyword) => {
+				Err(format!("expected a number or \"lan\", got \"{keyword}\""))
+			}
+		}
+	}
 }
 
 #[derive(Clone, Debug)]
-pub struct User<'a> {
-	pub hash: PasswordHash<'a>,
+pub struct User {
+	pub hash: PasswordHashString,
 	pub domains: HashMap<String, Domain>,
+	pub allow_ipv4: bool,
+	pub allow_ipv6: bool,
+	pub allow_ipv6lanprefix: bool,
+	pub role: Role,
+	/// Users of the same site share an uplink, a LAN prefix sent by one of them applies to the domains of all of them
+	pub site: Option<String>,
+	pub quota: Quota,
+	/// Replaces `geoip.allowed_countries` for this user
+	pub allowed_countries: Option<Vec<String>>,
+}
+
+/// Limits for users of deployments shared with friends or customers, everything is unlimited by default
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Quota {
+	/// Only the first domains of a request are updated, sorted by name unless the request lists them
+	pub max_domains: Option<NonZeroUsize>,
+	/// Lower TTLs of the domains are raised to this
+	pub min_ttl: Option<u32>,
+	/// Further update requests are refused until a day has passed since the first one
+	pub max_updates_per_day: Option<NonZeroU32>,
+}
+
+/// What to do when the `ipv4` parameter differs from the IPv4 address the request comes from,
+/// which happens when the client is behind another NAT than it thinks or the request is spoofed
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceCheck {
+	#[default]
+	Off,
+	Warn,
+	Reject,
+}
+
+/// Admins may update any domain by naming it in the `domain` parameter
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	#[default]
+	User,
+	Admin,
+}
+
+fn parse_group(group: &str) -> Result<Gid> {
+	if let Ok(gid) = group.parse() {
+		return Ok(Gid::from_raw(gid));
+	}
+	match Group::from_name(group)? {
+		Some(group) => Ok(group.gid),
+		None => Err(eyre!("Group {group} does not exist")),
+	}
+}
+
+fn parse_listen(raw_listen: RawListen) -> Result<Listen> {
+	let ips = match raw_listen.ip {
+		None => vec![],
+		Some(RawIps::One(ip)) => vec![ip],
+		Some(RawIps::Many(ips)) => {
+			if ips.is_empty() {
+				return Err(eyre!("The list of IP addresses to listen on is empty"));
+			}
+			ips
+		}
+	};
+	let proxy_protocol = match raw_listen.proxy_protocol {
+		None => vec![false; ips.len()],
+		Some(RawProxyProtocol::All(true)) if ips.is_empty() => {
+			return Err(eyre!(
+				"`listen.proxy_protocol` is set but `listen.ip` is not, use `listen.unix_proxy_protocol` for the Unix socket"
+			));
+		}
+		Some(RawProxyProtocol::All(enabled)) => vec![enabled; ips.len()],
+		Some(RawProxyProtocol::PerAddress(enabled)) => {
+			if enabled.len() != ips.len() {
+				return Err(eyre!(
+					"`listen.proxy_protocol` has {} entries but `listen.ip` has {}",
+					enabled.len(),
+					ips.len()
+				));
+			}
+			enabled
+		}
+	};
+	let addrs = match (ips.is_empty(), raw_listen.port) {
+		(true, _) => vec![],
+		(false, None) => {
+			return Err(eyre!("`listen.ip` is set but `listen.port` is not"));
+		}
+		(false, Some(port)) => ips
+			.into_iter()
+			.zip(proxy_protocol)
+			.map(|(ip, proxy_protocol)| ListenAddr {
+				addr: SocketAddr::from((ip, port)),
+				proxy_protocol,
+			})
+			.collect(),
+	};
+	if addrs.is_empty() && raw_listen.unix.is_none() {
+		return Err(eyre!("Neither `listen.ip` nor `listen.unix` is set"));
+	}
+	if raw_listen.unix.is_none()
+		&& (raw_listen.unix_mode.is_some()
+			|| raw_listen.unix_group.is_some()
+			|| raw_listen.unix_proxy_protocol)
+	{
+		return Err(eyre!(
+			"`listen.unix_mode`, `listen.unix_group` or `listen.unix_proxy_protocol` is set but `listen.unix` is not"
+		));
+	}
+	let group = raw_listen
+		.unix_group
+		.map(|group| parse_group(&group))
+		.transpose()
+		.wrap_err("Cannot parse `listen.unix_group`")?;
+	let mode = raw_listen
+		.unix_mode
+		.map(|mode| u32::from_str_radix(&mode, 8))
+		.transpose()
+		.wrap_err("Cannot parse `listen.unix_mode` as an octal number")?;
+	let unix = raw_listen.unix.map(|path| UnixSocket {
+		path,
+		mode,
+		group,
+		proxy_protocol: raw_listen.unix_proxy_protocol,
+	});
+	// Only the header the proxies write can be trusted, clients can send any other one themselves
+	let trusted_proxies = match (
+		raw_listen.trusted_proxy_depth.unwrap_or(0),
+		raw_listen.forwarded_header,
+	) {
+		(0, None) => None,
+		(0, Some(_)) => {
+			return Err(eyre!(
+				"`listen.forwarded_header` is set but `listen.trusted_proxy_depth` is not"
+			));
+		}
+		(_, None) => {
+			return Err(eyre!(
+				"`listen.trusted_proxy_depth` needs `listen.forwarded_header` to be set to the header the proxies write"
+			));
+		}
+		(depth, Some(header)) => Some(TrustedProxies { header, depth }),
+	};
+	Ok(Listen {
+		addrs,
+		v6only: raw_listen.v6only,
+		unix,
+		trusted_proxies,
+	})
+}
+
+fn parse_cors(raw_cors: RawCors) -> Result<Cors> {
+	let allowed_origins = if raw_cors.allowed_origins.iter().any(|origin| origin == "*") {
+		None
+	} else {
+		for origin in &raw_cors.allowed_origins {
+			// An origin is only a scheme and a host with an optional port, e.g. `https://example.org`
+			let is_valid = origin.parse::<Uri>().is_ok_and(|uri| {
+				uri.scheme().is_some()
+					&& uri.authority().is_some()
+					&& uri.path() == "/"
+					&& !origin.ends_with('/')
+					&& uri.query().is_none()
+			});
+			if !is_valid {
+				return Err(eyre!("Invalid CORS origin `{origin}`"));
+			}
+		}
+		Some(raw_cors.allowed_origins)
+	};
+	let allowed_methods = raw_cors
+		.allowed_methods
+		.unwrap_or_else(|| vec!["GET".to_string()])
+		.iter()
+		.map(|method| {
+			method
+				.parse()
+				.wrap_err_with(|| format!("Invalid CORS method `{method}`"))
+		})
+		.collect::<Result<_>>()?;
+	Ok(Cors {
+		allowed_origins,
+		allowed_methods,
+	})
+}
+
+fn check_update_program(
+	update_program: UpdateProgram,
+	features: Features,
+) -> Result<UpdateProgram> {
+	let families = [
+		("ipv4", features.ipv4, update_program.ipv4.is_some()),
+		("ipv6", features.ipv6, update_program.ipv6.is_some()),
+	];
+	for (family, enabled, configured) in families {
+		if enabled && !configured {
+			return Err(eyre!(
+				"The [update_program.{family}] section is missing, it is only optional with `features.{family} = false`"
+			));
+		}
+	}
+	let programs = [
+		Some(update_program.clone()),
+		update_program.for_family(update_program.ipv4.as_ref()),
+		update_program.for_family(update_program.ipv6.as_ref()),
+	];
+	for program in programs.iter().flatten() {
+		if program.mode == Some(UpdateMode::Batch) && program.has_placeholders() {
+			return Err(eyre!(
+				"The arguments or environment of the update program contain placeholders, which cannot be used in batch mode"
+			));
+		}
+	}
+	Ok(update_program)
+}
+
+fn check_shadow_program(
+	shadow_program: Option<UpdateProgram>,
+	features: Features,
+) -> Result<Option<UpdateProgram>> {
+	shadow_program
+		.map(|program| check_update_program(program, features))
+		.transpose()
+		.wrap_err("Invalid [shadow_program]")
+}
+
+fn check_parameter_aliases(aliases: HashMap<String, String>) -> Result<HashMap<String, String>> {
+	for (alias, name) in &aliases {
+		if PARAMETER_NAMES.contains(&alias.as_str()) {
+			return Err(eyre!(
+				"The parameter alias `{alias}` is already the name of a parameter"
+			));
+		}
+		if !PARAMETER_NAMES.contains(&name.as_str()) {
+			return Err(eyre!(
+				"The parameter alias `{alias}` refers to the unknown parameter `{name}`"
+			));
+		}
+	}
+	Ok(aliases)
+}
+
+fn check_password_policy(policy: &RawPasswordPolicy, hash: &PasswordHash) -> Result<()> {
+	if policy.min_memory.is_none() && policy.min_iterations.is_none() {
+		return Ok(());
+	}
+	let params = Params::try_from(hash)
+		.map_err(|e| eyre!("{e}"))
+		.wrap_err("Cannot read the argon2 parameters of the password hash")?;
+	if let Some(min_memory) = policy.min_memory {
+		if params.m_cost() < min_memory {
+			return Err(eyre!(
+				"The password hash uses {} KiB of memory but at least {min_memory} KiB are required",
+				params.m_cost()
+			));
+		}
+	}
+	if let Some(min_iterations) = policy.min_iterations {
+		if params.t_cost() < min_iterations {
+			return Err(eyre!(
+				"The password hash uses {} iterations but at least {min_iterations} are required",
+				params.t_cost()
+			));
+		}
+	}
+	Ok(())
+}
+
+fn parse_limits(raw_limits: &RawLimits) -> Result<Limits> {
+	let request_timeout = raw_limits.request_timeout.unwrap_or(30);
+	if request_timeout == 0 {
+		return Err(eyre!("The request timeout must not be zero"));
+	}
+	let header_read_timeout = raw_limits.header_read_timeout.unwrap_or(10);
+	if header_read_timeout == 0 {
+		return Err(eyre!("The header read timeout must not be zero"));
+	}
+	Ok(Limits {
+		request_timeout: Duration::from_secs(request_timeout),
+		max_query_length: raw_limits.max_query_length.unwrap_or(1024),
+		max_header_size: raw_limits.max_header_size.unwrap_or(8192),
+		max_body_size: raw_limits.max_body_size.unwrap_or(1024),
+		header_read_timeout: Duration::from_secs(header_read_timeout),
+		idle_timeout: Duration::from_secs(raw_limits.idle_timeout.unwrap_or(60)),
+		max_connections_per_ip: raw_limits.max_connections_per_ip.unwrap_or(0),
+	})
+}
+
+fn parse_circuit_breaker(raw_circuit_breaker: &RawCircuitBreaker) -> Result<CircuitBreaker> {
+	let failures = raw_circuit_breaker.failures.unwrap_or(5);
+	if failures == 0 {
+		return Err(eyre!(
+			"The number of failures of the circuit breaker must not be zero"
+		));
+	}
+	Ok(CircuitBreaker {
+		failures,
+		cooldown: Duration::from_secs(raw_circuit_breaker.cooldown.unwrap_or(60)),
+	})
+}
+
+fn parse_metrics(raw_metrics: &RawMetrics) -> Metrics {
+	Metrics {
+		max_user_labels: raw_metrics.max_user_labels.unwrap_or(0),
+		zones: raw_metrics
+			.zones
+			.iter()
+			.map(|zone| zone.trim_end_matches('.').to_string())
+			.collect(),
+		max_backend_labels: raw_metrics.max_backend_labels.unwrap_or(100),
+	}
+}
+
+fn parse_mqtt(raw_mqtt: RawMqtt) -> Result<Mqtt> {
+	let password = raw_mqtt
+		.password_file
+		.map(|password_file| {
+			let password = fs::read_to_string(&password_file).wrap_err_with(|| {
+				format!(
+					"Cannot read MQTT password file `{}`",
+					password_file.display()
+				)
+			})?;
+			Ok::<_, color_eyre::Report>(password.trim_end_matches('\n').as_bytes().to_vec())
+		})
+		.transpose()?;
+	Ok(Mqtt {
+		server: raw_mqtt.server,
+		topic: raw_mqtt
+			.topic
+			.unwrap_or_else(|| "dyndnsd/{domain}".to_string()),
+		client_id: raw_mqtt.client_id.unwrap_or_else(|| "dyndnsd".to_string()),
+		username: raw_mqtt.username,
+		password,
+	})
+}
+
+fn parse_geoip(raw_geoip: &RawGeoIp) -> Result<GeoIp> {
+	let reader = maxminddb::Reader::open_readfile(&raw_geoip.database).wrap_err_with(|| {
+		format!(
+			"Cannot read GeoIP database `{}`",
+			raw_geoip.database.display()
+		)
+	})?;
+	Ok(GeoIp {
+		reader: Arc::new(reader),
+		allowed_countries: raw_geoip
+			.allowed_countries
+			.iter()
+			.map(|country| country.to_ascii_uppercase())
+			.collect(),
+	})
+}
+
+/// At least one address family must be enabled and static addresses need their family
+fn check_features(config: &Config) -> Result<()> {
+	let features = config.features;
+	if !features.ipv4 && !features.ipv6 {
+		return Err(eyre!(
+			"At least one of features.ipv4 and features.ipv6 must be enabled"
+		));
+	}
+	for user in config.users.values() {
+		for (name, domain) in &user.domains {
+			if !features.ipv4 && domain.static_ipv4.is_some() {
+				return Err(eyre!(
+					"Domain {name} has static_ipv4 but features.ipv4 is disabled"
+				));
+			}
+			if !features.ipv6 && domain.static_ipv6.is_some() {
+				return Err(eyre!(
+					"Domain {name} has static_ipv6 but features.ipv6 is disabled"
+				));
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Countries can only be restricted per user with a `[geoip]` database
+fn check_allowed_countries(config: &Config) -> Result<()> {
+	if config.geoip.is_some() {
+		return Ok(());
+	}
+	let username = config
+		.users
+		.iter()
+		.find_map(|(username, user)| user.allowed_countries.as_ref().map(|_| username));
+	if let Some(username) = username {
+		return Err(eyre!(
+			"User {username} has allowed_countries but the [geoip] section is missing"
+		));
+	}
+	Ok(())
+}
+
+fn read_pepper(pepper_file: &Path) -> Result<Vec<u8>> {
+	let pepper = fs::read(pepper_file)
+		.wrap_err_with(|| format!("Cannot read pepper file `{}`", pepper_file.display()))?;
+	if pepper.is_empty() {
+		return Err(eyre!(
+			"The pepper file `{}` is empty",
+			pepper_file.display()
+		));
+	}
+	Ok(pepper)
+}
+
+/// The files to read for the paths given on the command line, in the order in which they are merged.
+/// Directories stand for the `.toml` files in them, sorted by name.
+fn config_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	for path in paths {
+		if !path.is_dir() {
+			files.push(path.clone());
+			continue;
+		}
+		let mut entries = fs::read_dir(path)
+			.wrap_err_with(|| format!("Cannot read config directory `{}`", path.display()))?
+			.map(|entry| entry.map(|entry| entry.path()))
+			.collect::<Result<Vec<_>, _>>()
+			.wrap_err_with(|| format!("Cannot read config directory `{}`", path.display()))?;
+		entries.retain(|entry| {
+			entry
+				.extension()
+				.is_some_and(|extension| extension == "toml")
+		});
+		entries.sort();
+		files.extend(entries);
+	}
+	Ok(files)
+}
+
+/// Merge `table` into `merged`, tables are merged recursively and all other values are replaced
+fn merge(merged: &mut toml::Table, table: toml::Table) {
+	for (key, value) in table {
+		match (merged.get_mut(&key), value) {
+			(Some(toml::Value::Table(merged)), toml::Value::Table(table)) => merge(merged, table),
+			(_, value) => {
+				merged.insert(key, value);
+			}
+		}
+	}
+}
+
+/// Deserialize the config, with a warning for every key that is not used, e.g. because of a typo
+fn deserialize_raw<'de, D>(deserializer: D) -> Result<(RawConfig, Vec<String>)>
+where
+	D: Deserializer<'de>,
+	D::Error: std::error::Error + Send + Sync + 'static,
+{
+	let mut warnings = Vec::new();
+	let raw_config = serde_ignored::deserialize(deserializer, |path| {
+		let warning = format!("Unknown key `{path}` is ignored");
+		warn!("{warning}");
+		warnings.push(warning);
+	})?;
+	Ok((raw_config, warnings))
 }
 
-impl Config<'_> {
-	pub fn read(filename: &Path) -> Result<Config<'static>> {
-		let contents = fs::read_to_string(filename)
-			.wrap_err_with(|| format!("Cannot read config file `{}`", filename.display()))?;
-		let config_parse_err_msg = || format!("Cannot parse config file `{}`", filename.display());
-		let raw_config: RawConfig =
-			toml::from_str(&contents).wrap_err_with(config_parse_err_msg)?;
+impl Config {
+	/// Read the config files and merge them, later files override the settings of earlier ones
+	pub fn read(paths: &[PathBuf]) -> Result<Self> {
+		let files = config_files(paths)?;
+		let mut merged = toml::Table::new();
+		for file in &files {
+			let contents = fs::read_to_string(file)
+				.wrap_err_with(|| format!("Cannot read config file `{}`", file.display()))?;
+			let table = contents
+				.parse()
+				.wrap_err_with(|| format!("Cannot parse config file `{}`", file.display()))?;
+			merge(&mut merged, table);
+		}
+		let names: Vec<_> = files
+			.iter()
+			.map(|file| format!("`{}`", file.display()))
+			.collect();
+		let names = if names.len() == 1 {
+			format!("config file {}", names[0])
+		} else {
+			format!("config files {}", names.join(", "))
+		};
+		deserialize_raw(toml::Value::Table(merged))
+			.and_then(|(raw_config, warnings)| Self::from_raw(raw_config, warnings))
+			.wrap_err_with(|| format!("Cannot parse {names}"))
+	}
+
+	/// Parse and validate the contents of a config file
+	pub fn parse(contents: &str) -> Result<Self> {
+		let (raw_config, warnings) = deserialize_raw(toml::Deserializer::new(contents))?;
+		Self::from_raw(raw_config, warnings)
+	}
+
+	/// Validate the deserialized config, adding to the `warnings` found while deserializing it
+	fn from_raw(raw_config: RawConfig, mut warnings: Vec<String>) -> Result<Self> {
+		let password_policy = &raw_config.password_policy;
+		let mut warn = |warning: String| {
+			warn!("{warning}");
+			warnings.push(warning);
+		};
 		let users: Result<HashMap<_, _>> = raw_config
 			.users
 			.into_iter()
@@ -81,30 +1083,87 @@ impl Config<'_> {
 						|| {
 							format!("Cannot parse ipv6prefixlen for user {username} and domain {domain}")
 						};
-					if props.ipv6prefixlen > 128 {
-						let prefixlen = props.ipv6prefixlen;
+					if let Ipv6PrefixLen::Fixed(prefixlen @ 129..) = props.ipv6prefixlen {
 						return Err(eyre!("Prefix is longer than 128 bits: {prefixlen}"))
-							.wrap_err_with(ipv6prefixlen_parse_err_msg)
-							.wrap_err_with(config_parse_err_msg);
-					};
+							.wrap_err_with(ipv6prefixlen_parse_err_msg);
+					}
+					if props.ipv6prefixlen == Ipv6PrefixLen::Fixed(0) && props.static_ipv6.is_none() {
+						warn(format!("The IPv6 address of domain {domain} of user {username} is never updated because its ipv6prefixlen is 0"));
+					}
+				}
+				let hash = PasswordHash::new(&raw_user.hash)
+					.wrap_err_with(|| format!("Cannot parse password hash of user {username}"))?;
+				if let Err(e) = check_password_policy(password_policy, &hash) {
+					if password_policy.on_violation == PolicyViolation::Refuse {
+						return Err(e)
+							.wrap_err_with(|| format!("Weak password hash of user {username}"));
+					}
+					warn(format!("Weak password hash of user {username}: {e}"));
 				}
-				// TODO: figure out how to do this without leaking memory. I wish PasswordHash::new() took a String instead of &str
-				let raw_hash = Box::leak(Box::new(raw_user.hash));
 				let user = User {
-					// TODO: get rid of this piece of the code by somehow implementing deserialization for PasswordHash
-					hash: PasswordHash::new(raw_hash)
-						.wrap_err_with(|| format!("Cannot parse password hash of user {username}"))
-						.wrap_err_with(config_parse_err_msg)?,
+					hash: hash.serialize(),
 					domains: raw_user.domains,
+					allow_ipv4: raw_user.allow_ipv4.unwrap_or(true),
+					allow_ipv6: raw_user.allow_ipv6.unwrap_or(true),
+					allow_ipv6lanprefix: raw_user.allow_ipv6lanprefix.unwrap_or(true),
+					role: raw_user.role,
+					site: raw_user.site,
+					quota: raw_user.quota,
+					allowed_countries: raw_user.allowed_countries.map(|countries| {
+						countries.iter().map(|c| c.to_ascii_uppercase()).collect()
+					}),
 				};
 				Ok((username, user))
 			})
 			.collect();
-		let config = Config {
-			listen: SocketAddr::from((raw_config.listen.ip, raw_config.listen.port)),
-			update_program: raw_config.update_program,
+		let pepper = raw_config
+			.pepper_file
+			.map(|pepper_file| read_pepper(&pepper_file))
+			.transpose()?;
+		let config = Self {
+			listen: parse_listen(raw_config.listen)?,
+			limits: parse_limits(&raw_config.limits)?,
+			audit_log: raw_config.audit_log,
+			events_file: raw_config.events_file,
+			pepper,
+			dedupe_window: Duration::from_secs(raw_config.dedupe_window.unwrap_or(0)),
+			auth_failure_window: Duration::from_secs(raw_config.auth_failure_window.unwrap_or(0)),
+			ipv4_source_check: raw_config.ipv4_source_check,
+			history_length: raw_config.history_length.unwrap_or(10),
+			log_redaction: raw_config.log_redaction,
+			log_file: raw_config.log_file,
+			verify: raw_config.verify.map(|verify| Verify {
+				server: verify.server,
+				timeout: Duration::from_secs(verify.timeout.unwrap_or(10)),
+			}),
+			circuit_breaker: raw_config
+				.circuit_breaker
+				.as_ref()
+				.map(parse_circuit_breaker)
+				.transpose()?,
+			metrics: raw_config.metrics.as_ref().map(parse_metrics),
+			checkip: raw_config.checkip.map(|checkip| CheckIp {
+				rate_limit: checkip.rate_limit.unwrap_or(60),
+			}),
+			geoip: raw_config.geoip.as_ref().map(parse_geoip).transpose()?,
+			statsd: raw_config.statsd.map(|statsd| Statsd {
+				server: statsd.server,
+				prefix: statsd.prefix.unwrap_or_else(|| "dyndnsd".to_string()),
+			}),
+			cors: raw_config.cors.map(parse_cors).transpose()?,
+			mqtt: raw_config.mqtt.map(parse_mqtt).transpose()?,
+			notify: raw_config.notify,
+			web_ui: raw_config.web_ui,
+			parameter_aliases: check_parameter_aliases(raw_config.parameter_aliases)?,
+			responses: raw_config.responses,
+			features: raw_config.features,
+			update_program: check_update_program(raw_config.update_program, raw_config.features)?,
+			shadow_program: check_shadow_program(raw_config.shadow_program, raw_config.features)?,
 			users: users?,
+			warnings,
 		};
+		check_features(&config)?;
+		check_allowed_countries(&config)?;
 
 		Ok(config)
 	}
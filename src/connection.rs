@@ -0,0 +1,140 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Limits on connections, so that a few slow or malicious clients cannot exhaust the listener
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Number of open connections per client address
+#[derive(Debug, Default)]
+pub struct PerIp {
+	max: usize,
+	counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Keeps a connection counted until it is dropped
+pub struct PerIpGuard {
+	per_ip: Arc<PerIp>,
+	ip: IpAddr,
+}
+
+impl PerIp {
+	#[must_use]
+	pub fn new(max: usize) -> Self {
+		Self {
+			max,
+			counts: Mutex::default(),
+		}
+	}
+
+	/// Count a new connection from `ip`, or return `None` if it already has the maximum number of connections
+	pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<PerIpGuard> {
+		let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+		let count = counts.entry(ip).or_default();
+		if self.max != 0 && *count >= self.max {
+			return None;
+		}
+		*count += 1;
+		drop(counts);
+		Some(PerIpGuard {
+			per_ip: Arc::clone(self),
+			ip,
+		})
+	}
+}
+
+impl Drop for PerIpGuard {
+	fn drop(&mut self) {
+		let mut counts = self
+			.per_ip
+			.counts
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		if let Some(count) = counts.get_mut(&self.ip) {
+			*count -= 1;
+			if *count == 0 {
+				counts.remove(&self.ip);
+			}
+		}
+	}
+}
+
+/// A stream which fails with `TimedOut` when nothing was read or written for a while
+/// and no request is in progress on it
+pub struct IdleTimeout<S> {
+	inner: S,
+	timeout: Duration,
+	sleep: Pin<Box<Sleep>>,
+	in_progress: Arc<AtomicUsize>,
+}
+
+impl<S> IdleTimeout<S> {
+	/// `in_progress` is the number of requests on this connection which are not answered yet
+	pub fn new(inner: S, timeout: Duration, in_progress: Arc<AtomicUsize>) -> Self {
+		Self {
+			inner,
+			timeout,
+			sleep: Box::pin(tokio::time::sleep(timeout)),
+			in_progress,
+		}
+	}
+
+	fn reset(&mut self) {
+		let deadline = Instant::now() + self.timeout;
+		self.sleep.as_mut().reset(deadline);
+	}
+
+	/// Turn a pending operation into an error once the connection was idle for too long
+	fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+		if poll.is_ready() || self.in_progress.load(Ordering::Relaxed) != 0 {
+			self.reset();
+			return poll;
+		}
+		match self.sleep.as_mut().poll(cx) {
+			Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+		this.check(cx, poll)
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+		this.check(cx, poll)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
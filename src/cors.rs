@@ -0,0 +1,18 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::Cors;
+
+/// Build the CORS filter which answers preflight requests and adds the headers to all responses.
+/// Requests with an origin which is not allowed are rejected with 403 Forbidden.
+#[must_use]
+pub fn filter(cors: &Cors) -> warp::cors::Builder {
+	let builder = warp::cors()
+		.allow_methods(cors.allowed_methods.clone())
+		// Let scripts read the headers which help to handle errors
+		.expose_headers(["retry-after", "x-request-id"]);
+	match &cors.allowed_origins {
+		None => builder.allow_any_origin(),
+		Some(origins) => builder.allow_origins(origins.iter().map(String::as_str)),
+	}
+}
@@ -0,0 +1,73 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Routers often send the same update twice in quick succession, only run the update program once for those
+
+use crate::process::DomainUpdate;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use warp::http::StatusCode;
+
+/// Identifies requests which would result in exactly the same update
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+	user: String,
+	updates: Vec<(String, u32, Option<Ipv4Addr>, Option<Ipv6Addr>)>,
+	verify: bool,
+}
+
+impl Key {
+	#[must_use]
+	pub fn new(user: &str, updates: &[DomainUpdate], verify: bool) -> Self {
+		Self {
+			user: user.to_string(),
+			updates: updates
+				.iter()
+				.map(|update| {
+					(
+						update.domain.to_string(),
+						update.ttl,
+						update.ipv4,
+						update.ipv6,
+					)
+				})
+				.collect(),
+			verify,
+		}
+	}
+}
+
+/// The status and body of the response and the domains for the event of the request
+pub type Reply = (StatusCode, String, Vec<Value>);
+
+#[derive(Debug)]
+struct Entry {
+	started: Instant,
+	reply: Arc<OnceCell<Reply>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Dedupe {
+	entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl Dedupe {
+	/// Get the reply of an identical request from the last `window`, which may still be in progress.
+	/// The caller initializes it if it is new.
+	pub fn get(&self, key: Key, window: Duration) -> Arc<OnceCell<Reply>> {
+		let now = Instant::now();
+		let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		entries.retain(|_, entry| now.duration_since(entry.started) < window);
+		let entry = entries.entry(key).or_insert_with(|| Entry {
+			started: now,
+			reply: Arc::new(OnceCell::new()),
+		});
+		let reply = Arc::clone(&entry.reply);
+		drop(entries);
+		reply
+	}
+}
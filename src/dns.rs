@@ -0,0 +1,154 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Just enough of a DNS client to ask a single server for the A or AAAA records of a name
+// and to tell secondary servers that a zone changed
+
+use color_eyre::eyre::{eyre, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordType {
+	A,
+	Aaaa,
+	Soa,
+}
+
+impl RecordType {
+	const fn code(self) -> u16 {
+		match self {
+			Self::A => 1,
+			Self::Aaaa => 28,
+			Self::Soa => 6,
+		}
+	}
+}
+
+// Opcode and flags in the first byte of the flags field
+const QUERY: u8 = 0;
+const NOTIFY: u8 = 4 << 3 | 0x04; // Opcode NOTIFY with the authoritative answer flag
+
+fn build_query(id: u16, name: &str, record_type: RecordType, opcode: u8) -> Result<Vec<u8>> {
+	let mut packet = Vec::with_capacity(512);
+	packet.extend_from_slice(&id.to_be_bytes());
+	// Flags: no recursion, since we talk to the authoritative server
+	packet.extend_from_slice(&[opcode, 0]);
+	// One question, no other records
+	packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+	for label in name.trim_end_matches('.').split('.') {
+		let length = u8::try_from(label.len())
+			.ok()
+			.filter(|&length| (1..64).contains(&length))
+			.ok_or_else(|| eyre!("Invalid label `{label}` in name `{name}`"))?;
+		packet.push(length);
+		packet.extend_from_slice(label.as_bytes());
+	}
+	packet.push(0);
+	packet.extend_from_slice(&record_type.code().to_be_bytes());
+	// Class IN
+	packet.extend_from_slice(&1u16.to_be_bytes());
+	Ok(packet)
+}
+
+/// Return the position right after the (possibly compressed) name starting at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Result<usize> {
+	loop {
+		let length = *packet.get(pos).ok_or_else(|| eyre!("Truncated name"))?;
+		match length {
+			0 => return Ok(pos + 1),
+			// Compression pointer, the name ends here
+			0xc0..=0xff => return Ok(pos + 2),
+			_ => pos += 1 + usize::from(length),
+		}
+	}
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16> {
+	let bytes = packet
+		.get(pos..pos + 2)
+		.ok_or_else(|| eyre!("Truncated response"))?;
+	Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn parse_response(packet: &[u8], record_type: RecordType) -> Result<Vec<IpAddr>> {
+	if packet.len() < 12 {
+		return Err(eyre!("Truncated response"));
+	}
+	let rcode = packet[3] & 0x0f;
+	// NXDOMAIN simply means there are no records yet
+	if rcode != 0 && rcode != 3 {
+		return Err(eyre!("Server answered with error code {rcode}"));
+	}
+	let questions = read_u16(packet, 4)?;
+	let answers = read_u16(packet, 6)?;
+	let mut pos = 12;
+	for _ in 0..questions {
+		pos = skip_name(packet, pos)? + 4;
+	}
+	let mut addresses = Vec::new();
+	for _ in 0..answers {
+		pos = skip_name(packet, pos)?;
+		let answer_type = read_u16(packet, pos)?;
+		let length = usize::from(read_u16(packet, pos + 8)?);
+		pos += 10;
+		let data = packet
+			.get(pos..pos + length)
+			.ok_or_else(|| eyre!("Truncated response"))?;
+		pos += length;
+		if answer_type != record_type.code() {
+			continue;
+		}
+		let address = match record_type {
+			RecordType::A => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(data)?)),
+			RecordType::Aaaa => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(data)?)),
+			// Not an address, only used for NOTIFY
+			RecordType::Soa => continue,
+		};
+		addresses.push(address);
+	}
+	Ok(addresses)
+}
+
+async fn exchange(server: SocketAddr, id: u16, query: &[u8]) -> Result<Vec<u8>> {
+	let bind_addr: SocketAddr = if server.is_ipv4() {
+		(Ipv4Addr::UNSPECIFIED, 0).into()
+	} else {
+		(Ipv6Addr::UNSPECIFIED, 0).into()
+	};
+	let socket = UdpSocket::bind(bind_addr).await?;
+	socket.connect(server).await?;
+	socket.send(query).await?;
+	let mut buf = [0; 4096];
+	loop {
+		let length = socket.recv(&mut buf).await?;
+		// Ignore stray packets, the caller's timeout takes care of a lost response
+		if buf[..length].starts_with(&id.to_be_bytes()) {
+			return Ok(buf[..length].to_vec());
+		}
+	}
+}
+
+/// Ask `server` for the records of type `record_type` of `name`.
+/// This does not time out on its own, wrap it in a timeout.
+pub async fn query(server: SocketAddr, name: &str, record_type: RecordType) -> Result<Vec<IpAddr>> {
+	let id = rand::random();
+	let query = build_query(id, name, record_type, QUERY)?;
+	let response = exchange(server, id, &query).await?;
+	parse_response(&response, record_type)
+}
+
+/// Tell the secondary `server` that `zone` changed, so it transfers the zone right away.
+/// This does not time out on its own, wrap it in a timeout.
+pub async fn notify(server: SocketAddr, zone: &str) -> Result<()> {
+	let id = rand::random();
+	let query = build_query(id, zone, RecordType::Soa, NOTIFY)?;
+	let response = exchange(server, id, &query).await?;
+	if response.len() < 12 || response[2] & 0x80 == 0 {
+		return Err(eyre!("Invalid response"));
+	}
+	match response[3] & 0x0f {
+		0 => Ok(()),
+		rcode => Err(eyre!("Server answered with error code {rcode}")),
+	}
+}
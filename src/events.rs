@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// One JSON object per line for every update request, for programs which want to follow the updates
+
+use crate::process::DomainUpdate;
+use crate::request_id::RequestId;
+use crate::server::ClientAddr;
+use log::{error, warn};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use warp::http::StatusCode;
+
+tokio::task_local! {
+	static DOMAINS: RefCell<Vec<Value>>;
+}
+
+/// Run `f` and return its output together with the domains passed to `record_domains()` while it ran
+pub async fn collect<F: Future>(f: F) -> (F::Output, Vec<Value>) {
+	DOMAINS
+		.scope(RefCell::default(), async {
+			let output = f.await;
+			(output, DOMAINS.with(RefCell::take))
+		})
+		.await
+}
+
+/// Remember which domains the update program was run for and whether it succeeded for each of them
+pub fn record_domains(updates: &[DomainUpdate], succeeded: &[bool]) {
+	let _ = DOMAINS.try_with(|domains| {
+		domains
+			.borrow_mut()
+			.extend(updates.iter().zip(succeeded).map(|(update, succeeded)| {
+				json!({
+					"domain": update.domain,
+					"ttl": update.ttl,
+					"ipv4": update.ipv4,
+					"ipv6": update.ipv6,
+					"success": succeeded,
+				})
+			}));
+	});
+}
+
+/// Add the domains collected by a nested `collect()`, like the ones of the original request to the event of a duplicate
+pub fn add_domains(domains: &[Value]) {
+	let _ = DOMAINS.try_with(|collected| collected.borrow_mut().extend_from_slice(domains));
+}
+
+/// How many events may wait to be written before further events are dropped
+const QUEUE_LENGTH: usize = 1024;
+
+/// Appends the events to the events file in a background thread, so writing them never delays a response.
+///
+/// If the file cannot be written fast enough, further events are dropped until there is room in the queue again.
+/// A named pipe is opened without blocking, so events are also dropped while it has no reader.
+#[derive(Debug, Default)]
+pub struct Writer {
+	queue: OnceLock<mpsc::Sender<String>>,
+}
+
+impl Writer {
+	/// Queue the event of a finished update request for writing to the events file at `path`
+	pub fn write(
+		&self,
+		path: &Path,
+		id: RequestId,
+		user: &str,
+		client: ClientAddr,
+		status: StatusCode,
+		domains: &[Value],
+	) {
+		let event = json!({
+			"time": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+			"request_id": id.to_string(),
+			"user": user,
+			"client": client.0,
+			"status": status.as_u16(),
+			"success": status.is_success(),
+			"domains": domains,
+		});
+		let queue = self.queue.get_or_init(|| {
+			let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+			let path = path.to_path_buf();
+			// A thread instead of a blocking task, so a stuck reader of a named pipe cannot delay the shutdown
+			thread::spawn(move || append_lines(&path, receiver));
+			sender
+		});
+		if queue.try_send(event.to_string() + "\n").is_err() {
+			warn!(
+				"Dropping the event of request {id}, the events file `{}` is not written fast enough",
+				path.display()
+			);
+		}
+	}
+}
+
+/// Errors are only logged since the requests were already handled
+fn append_lines(path: &Path, mut receiver: mpsc::Receiver<String>) {
+	while let Some(line) = receiver.blocking_recv() {
+		if let Err(e) = append_line(path, &line) {
+			error!("Error writing to events file `{}`: {e}", path.display());
+		}
+	}
+}
+
+/// Open the file again for every line so that it can be rotated without restarting.
+/// Opening a named pipe without a reader fails instead of blocking, but the line itself is written blocking,
+/// so a slow reader gets it in one piece instead of a part of it when the pipe is full.
+fn append_line(path: &Path, line: &str) -> io::Result<()> {
+	let mut file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.custom_flags(OFlag::O_NONBLOCK.bits())
+		.open(path)?;
+	fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_APPEND))?;
+	file.write_all(line.as_bytes())
+}
@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Parser for the `Forwarded` header (RFC 7239) and the older `X-Forwarded-For` header added by reverse proxies
+
+use crate::config::{ForwardedHeader, TrustedProxies};
+use color_eyre::eyre::{eyre, Result};
+use std::iter::Peekable;
+use std::net::{IpAddr, SocketAddr};
+use std::str::Chars;
+use warp::http::{header::FORWARDED, HeaderMap};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The address of the client as seen by the outermost of the trusted proxies
+///
+/// Every proxy appends the address it got the request from, so this is the `depth`-th hop from the right.
+/// Only the header the proxies write is read, a client could send the other one itself.
+pub fn client(headers: &HeaderMap, proxies: TrustedProxies) -> Result<SocketAddr> {
+	let depth = proxies.depth;
+	let mut chain = Vec::new();
+	match proxies.header {
+		ForwardedHeader::Forwarded => {
+			for value in headers.get_all(FORWARDED) {
+				parse_forwarded(value.to_str()?, &mut chain)
+					.ok_or_else(|| eyre!("Invalid Forwarded header"))?;
+			}
+		}
+		ForwardedHeader::XForwardedFor => {
+			for value in headers.get_all(X_FORWARDED_FOR) {
+				chain.extend(
+					value
+						.to_str()?
+						.split(',')
+						.map(|node| Some(node.trim().to_string())),
+				);
+			}
+		}
+	}
+	let Some(index) = chain.len().checked_sub(depth) else {
+		return Err(eyre!(
+			"Only {} of {depth} proxies are listed in the forwarding header",
+			chain.len()
+		));
+	};
+	let node = chain[index]
+		.as_deref()
+		.ok_or_else(|| eyre!("The forwarding header does not say who the client is"))?;
+	parse_node(node).ok_or_else(|| eyre!("Client address `{node}` is not an IP address"))
+}
+
+/// An address like `192.0.2.43`, `192.0.2.43:47011`, `[2001:db8::1]:4711` or `2001:db8::1`.
+/// Returns `None` for `unknown` and obfuscated identifiers like `_hidden`.
+/// The port is 0 if it is missing or obfuscated.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+	if let Ok(addr) = node.parse() {
+		return Some(addr);
+	}
+	let host = match node.strip_prefix('[') {
+		Some(rest) => rest.split_once(']')?.0,
+		// An IPv4 address with an obfuscated port
+		None if node.matches(':').count() == 1 => node.split_once(':')?.0,
+		None => node,
+	};
+	host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+	while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+}
+
+const fn is_delimiter(c: char) -> bool {
+	matches!(c, ';' | ',' | '=' | '"' | ' ' | '\t')
+}
+
+fn parse_quoted(chars: &mut Peekable<Chars>) -> Option<String> {
+	let mut value = String::new();
+	loop {
+		match chars.next()? {
+			'"' => return Some(value),
+			'\\' => value.push(chars.next()?),
+			c => value.push(c),
+		}
+	}
+}
+
+/// Append the `for` parameter of every element of a `Forwarded` header value to `chain`,
+/// `None` for elements without one. Returns `None` if the value is malformed.
+fn parse_forwarded(value: &str, chain: &mut Vec<Option<String>>) -> Option<()> {
+	let mut chars = value.chars().peekable();
+	let mut node = None;
+	let mut empty_element = true;
+	loop {
+		skip_whitespace(&mut chars);
+		let mut name = String::new();
+		while let Some(c) = chars.next_if(|c| !is_delimiter(*c)) {
+			name.push(c);
+		}
+		if !name.is_empty() {
+			empty_element = false;
+			chars.next_if_eq(&'=')?;
+			let value = if chars.next_if_eq(&'"').is_some() {
+				parse_quoted(&mut chars)?
+			} else {
+				let mut value = String::new();
+				while let Some(c) = chars.next_if(|c| !is_delimiter(*c)) {
+					value.push(c);
+				}
+				value
+			};
+			if name.eq_ignore_ascii_case("for") {
+				node = Some(value);
+			}
+			skip_whitespace(&mut chars);
+		}
+		match chars.next() {
+			Some(';') if !name.is_empty() => {}
+			Some(',') | None => {
+				// The list syntax of HTTP allows empty elements, they are not hops
+				if !empty_element {
+					chain.push(node.take());
+				}
+				if chars.peek().is_none() {
+					return Some(());
+				}
+				empty_element = true;
+			}
+			_ => return None,
+		}
+	}
+}
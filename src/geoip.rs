@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Only accept updates from the countries the users live in, refusals are logged with this module as the target
+
+use crate::config::{GeoIp, User};
+use crate::logging::{Ip, Username};
+use crate::server::ClientAddr;
+use log::{debug, warn};
+use maxminddb::geoip2;
+use std::net::IpAddr;
+use warp::{http::StatusCode, reply::WithStatus};
+
+fn country(geoip: &GeoIp, ip: IpAddr) -> Option<String> {
+	match geoip.reader.lookup::<geoip2::Country>(ip) {
+		Ok(country) => country
+			.country
+			.and_then(|country| country.iso_code)
+			.map(str::to_string),
+		Err(e) => {
+			debug!("Cannot look up the country of {}: {e}", Ip(ip));
+			None
+		}
+	}
+}
+
+/// Refuse the request if the client is not in one of the countries allowed for the user
+pub fn check(
+	geoip: &GeoIp,
+	username: &str,
+	user: &User,
+	client: ClientAddr,
+) -> Result<(), WithStatus<String>> {
+	let allowed = user
+		.allowed_countries
+		.as_ref()
+		.unwrap_or(&geoip.allowed_countries);
+	if allowed.is_empty() {
+		return Ok(());
+	}
+	let country = client
+		.0
+		.and_then(|addr| country(geoip, addr.ip().to_canonical()));
+	if country
+		.as_ref()
+		.is_some_and(|country| allowed.contains(country))
+	{
+		return Ok(());
+	}
+	warn!(
+		"Refused update of user {} from {client} in country {}, allowed are {}",
+		Username(username),
+		country.as_deref().unwrap_or("unknown"),
+		allowed.join(", ")
+	);
+	Err(warp::reply::with_status(
+		"Country not allowed".to_string(),
+		StatusCode::FORBIDDEN,
+	))
+}
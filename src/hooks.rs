@@ -0,0 +1,56 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::background::Tasks;
+use crate::process::DomainUpdate;
+use log::{debug, error, warn};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Start the post-update hooks of a domain in the background.
+/// The update itself already succeeded, so the client does not wait for them and failures are only logged.
+pub fn run_post_update(tasks: &Tasks, commands: &[String], update: &DomainUpdate) {
+	if update.ipv4.is_none() && update.ipv6.is_none() {
+		return;
+	}
+	for hook in commands {
+		let mut command = Command::new("sh");
+		command
+			.arg("-c")
+			.arg(hook)
+			.env("DYNDNSD_DOMAIN", update.domain)
+			.env("DYNDNSD_TTL", update.ttl.to_string())
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped());
+		if let Some(ipv4) = update.ipv4 {
+			command.env("DYNDNSD_IPV4", ipv4.to_string());
+		}
+		if let Some(ipv6) = update.ipv6 {
+			command.env("DYNDNSD_IPV6", ipv6.to_string());
+		}
+		let hook = hook.clone();
+		let domain = update.domain.to_string();
+		tasks.spawn(async move {
+			debug!("Running post-update hook `{hook}` for {domain}");
+			match command.output().await {
+				Ok(output) if output.status.success() => {}
+				Ok(output) => {
+					warn!(
+						"Post-update hook `{hook}` for {domain} failed with {}",
+						output.status
+					);
+					let stdout = String::from_utf8_lossy(&output.stdout);
+					if !stdout.is_empty() {
+						warn!("and stdout: `{stdout}`");
+					}
+					let stderr = String::from_utf8_lossy(&output.stderr);
+					if !stderr.is_empty() {
+						warn!("and stderr: `{stderr}`");
+					}
+				}
+				Err(e) => error!("Error spawning post-update hook `{hook}` for {domain}: {e}"),
+			}
+		});
+	}
+}
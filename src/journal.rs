@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Native protocol of the systemd journal, so every entry carries the user, domains, request ID and source address as fields
+
+use crate::logging;
+use env_logger::Logger;
+use log::{Level, Log, Metadata, Record};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends every log record as one datagram to the journal
+pub struct Journal {
+	filter: Logger,
+	socket: UnixDatagram,
+}
+
+impl Journal {
+	/// Connect to the journal listening on the socket at `path`
+	pub fn connect(filter: Logger, path: &Path) -> io::Result<Self> {
+		let socket = UnixDatagram::unbound()?;
+		socket.connect(path)?;
+		Ok(Self { filter, socket })
+	}
+
+	/// Entries which are too large for a datagram are passed in a sealed memfd, as the native protocol requires
+	fn send_memfd(&self, entry: &[u8]) -> io::Result<()> {
+		let flags = MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING;
+		let mut file = File::from(memfd_create(c"dyndnsd-journal", flags)?);
+		file.write_all(entry)?;
+		let seals = SealFlag::F_SEAL_SHRINK
+			| SealFlag::F_SEAL_GROW
+			| SealFlag::F_SEAL_WRITE
+			| SealFlag::F_SEAL_SEAL;
+		fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals))?;
+		let fds = [file.as_raw_fd()];
+		sendmsg::<()>(
+			self.socket.as_raw_fd(),
+			&[],
+			&[ControlMessage::ScmRights(&fds)],
+			MsgFlags::empty(),
+			None,
+		)?;
+		Ok(())
+	}
+}
+
+/// Append a field in the format of the native protocol, values with newlines need the binary form
+fn push_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+	entry.extend(name.as_bytes());
+	if value.contains('\n') {
+		entry.push(b'\n');
+		entry.extend((value.len() as u64).to_le_bytes());
+	} else {
+		entry.push(b'=');
+	}
+	entry.extend(value.as_bytes());
+	entry.push(b'\n');
+}
+
+/// Datagrams larger than the send buffer of the socket fail with one of these errors
+fn is_too_large(e: &io::Error) -> bool {
+	e.raw_os_error()
+		.is_some_and(|errno| matches!(Errno::from_raw(errno), Errno::EMSGSIZE | Errno::ENOBUFS))
+}
+
+const fn priority(level: Level) -> &'static str {
+	match level {
+		Level::Error => "3",
+		Level::Warn => "4",
+		Level::Info => "6",
+		Level::Debug | Level::Trace => "7",
+	}
+}
+
+impl Log for Journal {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.filter.enabled(metadata)
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.filter.matches(record) {
+			return;
+		}
+		let mut entry = Vec::new();
+		push_field(&mut entry, "MESSAGE", &record.args().to_string());
+		push_field(&mut entry, "PRIORITY", priority(record.level()));
+		push_field(&mut entry, "SYSLOG_IDENTIFIER", "dyndnsd");
+		push_field(&mut entry, "CODE_MODULE", record.target());
+		if let (Some(file), Some(line)) = (record.file(), record.line()) {
+			push_field(&mut entry, "CODE_FILE", file);
+			push_field(&mut entry, "CODE_LINE", &line.to_string());
+		}
+		for (name, value) in logging::fields() {
+			push_field(&mut entry, name, &value);
+		}
+		let result = match self.socket.send(&entry) {
+			Err(e) if is_too_large(&e) => self.send_memfd(&entry),
+			result => result.map(drop),
+		};
+		if let Err(e) = result {
+			// There is nowhere else to log this to
+			let _ = writeln!(io::stderr(), "Cannot send log entry to the journal: {e}");
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+/// Log to the journal from now on, fails if it is not running
+pub fn init(filter: Logger) -> io::Result<()> {
+	let max_level = filter.filter();
+	let journal = Journal::connect(filter, Path::new(SOCKET))?;
+	log::set_max_level(max_level);
+	log::set_boxed_logger(Box::new(journal)).map_err(io::Error::other)
+}
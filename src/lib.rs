@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// The library only exists for the fuzz targets and the dyndnsd binary, it is not meant to be used by anything else
+#![allow(clippy::missing_errors_doc)]
+
+pub mod audit;
+pub mod auth_cache;
+pub mod background;
+pub mod bench;
+pub mod breaker;
+pub mod checkip;
+pub mod config;
+pub mod connection;
+pub mod cors;
+pub mod dedupe;
+pub mod dns;
+pub mod events;
+pub mod forwarded;
+pub mod geoip;
+pub mod hooks;
+pub mod journal;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod mqtt;
+pub mod notify;
+pub mod oneshot;
+pub mod password;
+pub mod process;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod refresh;
+pub mod request_id;
+pub mod selfsigned;
+pub mod server;
+pub mod state;
+pub mod statsd;
+pub mod status;
+pub mod web_ui;
@@ -0,0 +1,77 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::Limits;
+use log::warn;
+use warp::{
+	http::{HeaderMap, StatusCode},
+	reject::Reject,
+	Filter, Rejection, Reply,
+};
+
+#[derive(Debug)]
+struct UriTooLong;
+impl Reject for UriTooLong {}
+
+#[derive(Debug)]
+struct HeadersTooLarge;
+impl Reject for HeadersTooLarge {}
+
+#[derive(Debug)]
+struct PayloadTooLarge;
+impl Reject for PayloadTooLarge {}
+
+/// Reject requests which exceed the configured size limits before doing any other work
+pub fn check(limits: &Limits) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+	let max_query_length = limits.max_query_length;
+	let max_header_size = limits.max_header_size;
+	let max_body_size = limits.max_body_size;
+	warp::query::raw()
+		.or(warp::any().map(String::new))
+		.unify()
+		.and(warp::header::headers_cloned())
+		.and(warp::header::optional::<u64>("content-length"))
+		.and_then(
+			move |query: String, headers: HeaderMap, content_length: Option<u64>| async move {
+				if query.len() > max_query_length {
+					warn!(
+						"Query string is {} bytes long, which is more than the limit of {max_query_length} bytes",
+						query.len()
+					);
+					return Err(warp::reject::custom(UriTooLong));
+				}
+				let header_size: usize = headers
+					.iter()
+					.map(|(name, value)| name.as_str().len() + value.len())
+					.sum();
+				if header_size > max_header_size {
+					warn!("Headers are {header_size} bytes large, which is more than the limit of {max_header_size} bytes");
+					return Err(warp::reject::custom(HeadersTooLarge));
+				}
+				if let Some(content_length) = content_length {
+					if content_length > max_body_size {
+						warn!("Body is {content_length} bytes large, which is more than the limit of {max_body_size} bytes");
+						return Err(warp::reject::custom(PayloadTooLarge));
+					}
+				}
+				Ok(())
+			},
+		)
+		.untuple_one()
+}
+
+/// Turn the rejections from `check()` into the matching HTTP status codes
+#[allow(clippy::unused_async)] // `recover()` needs a future
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+	let status = if err.find::<UriTooLong>().is_some() {
+		StatusCode::URI_TOO_LONG
+	} else if err.find::<HeadersTooLarge>().is_some() {
+		StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+	} else if err.find::<PayloadTooLarge>().is_some() {
+		StatusCode::PAYLOAD_TOO_LARGE
+	} else {
+		return Err(err);
+	};
+	let reason = status.canonical_reason().unwrap_or("ERROR").to_string();
+	Ok(warp::reply::with_status(reason, status))
+}
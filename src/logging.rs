@@ -1,14 +1,227 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use env_logger::{Builder, Env};
-use std::io::Write;
+use crate::journal;
+use crate::request_id;
+use env_logger::{Builder, Env, Target};
+use serde_derive::Deserialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+/// How much of the usernames and addresses ends up in the log
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+	#[default]
+	None,
+	/// Only the first character of usernames and the network part of addresses
+	Partial,
+	Full,
+}
+
+static REDACTION: OnceLock<Redaction> = OnceLock::new();
+
+/// Set the redaction level for the rest of the process, later calls are ignored
+pub fn set_redaction(redaction: Redaction) {
+	let _ = REDACTION.set(redaction);
+}
+
+pub fn redaction() -> Redaction {
+	REDACTION.get().copied().unwrap_or_default()
+}
+
+/// A username, redacted as configured when displayed
+pub struct Username<'a>(pub &'a str);
+
+impl fmt::Display for Username<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (redaction(), self.0.chars().next()) {
+			(Redaction::None, _) => write!(f, "{}", self.0),
+			(Redaction::Partial, Some(first)) => write!(f, "{first}***"),
+			(Redaction::Partial | Redaction::Full, _) => write!(f, "<redacted>"),
+		}
+	}
+}
+
+/// An address, redacted as configured when displayed.
+/// Partial redaction keeps the /24 of IPv4 and the /48 of IPv6 addresses.
+pub struct Ip(pub IpAddr);
+
+impl fmt::Display for Ip {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (redaction(), self.0) {
+			(Redaction::None, ip) => write!(f, "{ip}"),
+			(Redaction::Partial, IpAddr::V4(ip)) => {
+				write!(f, "{}/24", Ipv4Addr::from(u32::from(ip) & !0xff))
+			}
+			(Redaction::Partial, IpAddr::V6(ip)) => {
+				write!(
+					f,
+					"{}/48",
+					Ipv6Addr::from(u128::from(ip) & !(u128::MAX >> 48))
+				)
+			}
+			(Redaction::Full, _) => write!(f, "<redacted>"),
+		}
+	}
+}
+
+/// Display an optional value as the value itself or `none`
+pub struct Opt<'a, T>(pub &'a Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Opt<'_, T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.0 {
+			Some(value) => value.fmt(f),
+			None => write!(f, "none"),
+		}
+	}
+}
+
+tokio::task_local! {
+	static CONTEXT: RefCell<Context>;
+}
+
+/// What the log entries of an update request are about, for the fields of journal entries
+#[derive(Default)]
+struct Context {
+	user: String,
+	source_ip: Option<IpAddr>,
+	domains: Vec<String>,
+}
+
+/// Run `f` with the user and source address of an update request as the context of its log entries
+pub async fn scope<F: Future>(user: &str, source_ip: Option<IpAddr>, f: F) -> F::Output {
+	let context = Context {
+		user: user.to_string(),
+		source_ip,
+		domains: Vec::new(),
+	};
+	CONTEXT.scope(RefCell::new(context), f).await
+}
+
+/// Add the domains an update request is about to the context of its log entries
+pub fn set_domains<'a>(domains: impl IntoIterator<Item = &'a str>) {
+	let _ = CONTEXT.try_with(|context| {
+		context.borrow_mut().domains = domains.into_iter().map(str::to_string).collect();
+	});
+}
+
+/// The fields of journal entries describing the current update request, redacted like the messages.
+/// There is one `DOMAIN` field per domain, so filtering for any of them finds the entry.
+#[must_use]
+pub fn fields() -> Vec<(&'static str, String)> {
+	CONTEXT
+		.try_with(|context| {
+			let context = context.borrow();
+			let mut fields = vec![
+				("USER", Username(&context.user).to_string()),
+				("REQUEST_ID", request_id::current()),
+			];
+			if let Some(source_ip) = context.source_ip {
+				fields.push(("SOURCE_IP", Ip(source_ip).to_string()));
+			}
+			fields.extend(
+				context
+					.domains
+					.iter()
+					.map(|domain| ("DOMAIN", domain.clone())),
+			);
+			fields
+		})
+		.unwrap_or_default()
+}
+
+struct LogFile {
+	path: PathBuf,
+	file: File,
+}
+
+/// The file the log is written to instead of stderr, if one is configured
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+	OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Write the log to `path` from now on
+pub fn set_log_file(path: &Path) -> io::Result<()> {
+	let file = open_log_file(path)?;
+	*LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner) = Some(LogFile {
+		path: path.to_path_buf(),
+		file,
+	});
+	Ok(())
+}
+
+/// Open the log file again, so that it can be rotated by renaming it.
+/// If that fails, the log keeps going to the old file.
+pub fn reopen_log_file() -> io::Result<()> {
+	let mut log_file = LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner);
+	if let Some(log_file) = log_file.as_mut() {
+		log_file.file = open_log_file(&log_file.path)?;
+	}
+	drop(log_file);
+	Ok(())
+}
+
+/// Where `env_logger` writes to, the log file or stderr
+struct Output;
+
+impl Write for Output {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match LOG_FILE
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.as_mut()
+		{
+			Some(log_file) => log_file.file.write(buf),
+			None => io::stderr().write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match LOG_FILE
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.as_mut()
+		{
+			Some(log_file) => log_file.file.flush(),
+			None => io::stderr().flush(),
+		}
+	}
+}
 
 pub fn setup() {
-	let env = Env::default().filter_or("RUST_LOG", "dyndnsd=info");
+	// The log goes through `Output`, for which `env_logger` cannot tell whether stderr is a terminal
+	let style = if io::stderr().is_terminal() {
+		"always"
+	} else {
+		"never"
+	};
+	let env = || {
+		Env::default()
+			.filter_or("RUST_LOG", "dyndnsd=info")
+			.write_style_or("RUST_LOG_STYLE", style)
+	};
 
 	match std::env::var("RUST_LOG_STYLE") {
-		Ok(s) if s == "SYSTEMD" => Builder::from_env(env)
+		Ok(s) if s == "JOURNAL" => {
+			if let Err(e) = journal::init(Builder::from_env(env()).build()) {
+				Builder::from_env(env())
+					.target(Target::Pipe(Box::new(Output)))
+					.init();
+				log::warn!("Cannot log to the journal, logging to stderr instead: {e}");
+			}
+		}
+		Ok(s) if s == "SYSTEMD" => Builder::from_env(env())
+			.target(Target::Pipe(Box::new(Output)))
 			.format(|buf, record| {
 				for line in record.args().to_string().lines() {
 					writeln!(
@@ -27,6 +240,8 @@ pub fn setup() {
 				Ok(())
 			})
 			.init(),
-		_ => env_logger::init_from_env(env),
+		_ => Builder::from_env(env())
+			.target(Target::Pipe(Box::new(Output)))
+			.init(),
 	}
 }
@@ -1,23 +1,186 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-mod config;
-mod logging;
-mod process;
-
-use crate::config::Config;
-use crate::process::{update, QueryParameters};
-use clap::Parser;
-use color_eyre::eyre::Result;
-use log::info;
-use warp::Filter;
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use dyndnsd::config::Config;
+use dyndnsd::process::{self, update, QueryParameters};
+use dyndnsd::server::ClientAddr;
+use dyndnsd::state::State;
+use dyndnsd::{
+	bench, checkip, cors, limits, logging, oneshot, password, refresh, selfsigned, server, status,
+	web_ui,
+};
+use log::{error, info};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use warp::{Filter, Reply};
 
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Args {
-	/// Path to the config file
+	/// Path to the config file, or to a directory of them.
+	/// Can be given multiple times, later files override the settings of earlier ones.
 	#[arg(short, long, default_value = "config.toml")]
-	config: std::path::PathBuf,
+	config: Vec<std::path::PathBuf>,
+
+	/// Refuse to start if the config causes any warnings
+	#[arg(long, global = true)]
+	strict: bool,
+
+	#[command(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Only check the config file and exit, with a non-zero status if it is invalid
+	Check,
+	/// Read a password from stdin and print its hash, using the pepper from the config file
+	HashPassword,
+	/// Update the domains of a user like an update request would, without starting the server.
+	/// This is useful for cron jobs on the DNS server itself and for trying out the update program templates.
+	Oneshot(oneshot::Args),
+	/// Send synthetic update requests to a running instance and report their latency.
+	/// The requests are dry runs, so the server does not run its update program.
+	Bench(bench::Args),
+	/// Create a self-signed certificate and key, for testing a TLS reverse proxy.
+	/// Browsers and `DynDNS` clients will warn about it, so use a real certificate once one is available.
+	GenSelfsigned(selfsigned::Args),
+	/// Run the update program again with the last known addresses of the domains of a user, e.g. after restoring the DNS server from a backup.
+	/// The addresses are taken from the successful updates in the `events_file`.
+	Refresh(refresh::Args),
+}
+
+/// Recent updates of the domains of a user
+fn status_route(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+	warp::get()
+		.and(warp::path("status"))
+		.and(warp::path::end())
+		.and(warp::ext::get::<ClientAddr>())
+		.and(warp::query::<HashMap<String, String>>())
+		.then({
+			let config = Arc::clone(config);
+			let state = Arc::clone(state);
+			move |client: ClientAddr, query: HashMap<String, String>| {
+				let config = Arc::clone(&config);
+				let state = Arc::clone(&state);
+				async move { status::reply(&config, &state, client, &query).await }
+			}
+		})
+}
+
+/// All endpoints of the server
+fn routes(config: &Arc<Config>, state: &Arc<State>) -> server::Routes {
+	let limits = limits::check(&config.limits);
+	let update = warp::path("update")
+		.and(warp::path::end())
+		.and(
+			warp::get()
+				.and(process::query(Arc::clone(config)))
+				.or(warp::post().and(process::form(Arc::clone(config))))
+				.unify(),
+		)
+		.and(warp::ext::get::<ClientAddr>())
+		.then({
+			let config = Arc::clone(config);
+			let state = Arc::clone(state);
+			move |q: QueryParameters, client: ClientAddr| {
+				let config = Arc::clone(&config);
+				let state = Arc::clone(&state);
+				async move { update(&config, &state, &q, client).await }
+			}
+		});
+
+	let metrics = warp::get()
+		.and(warp::path("metrics"))
+		.and(warp::path::end())
+		.and_then({
+			let config = Arc::clone(config);
+			let state = Arc::clone(state);
+			move || {
+				let config = Arc::clone(&config);
+				let state = Arc::clone(&state);
+				async move {
+					if config.metrics.is_none() {
+						return Err(warp::reject::not_found());
+					}
+					let circuit_breaker_open = state.breaker.retry_after().is_some();
+					Ok(state.metrics.render(circuit_breaker_open).into_response())
+				}
+			}
+		});
+
+	let checkip = warp::get()
+		.and(warp::path("checkip"))
+		.and(warp::path::end())
+		.and(warp::ext::get::<ClientAddr>())
+		.and(warp::query::<HashMap<String, String>>())
+		.and(warp::header::optional::<String>("accept"))
+		.and_then({
+			let config = Arc::clone(config);
+			let state = Arc::clone(state);
+			move |client: ClientAddr, query: HashMap<String, String>, accept: Option<String>| {
+				let config = Arc::clone(&config);
+				let state = Arc::clone(&state);
+				async move {
+					let Some(checkip) = &config.checkip else {
+						return Err(warp::reject::not_found());
+					};
+					let json = query.get("format").is_some_and(|format| format == "json")
+						|| accept.is_some_and(|accept| accept.contains("application/json"));
+					Ok(checkip::reply(checkip, &state.checkip, client, json))
+				}
+			}
+		});
+
+	let status = status_route(config, state);
+
+	let web_ui = warp::get()
+		.and(warp::path::end())
+		.and(warp::ext::get::<ClientAddr>())
+		.and(warp::header::optional::<String>("authorization"))
+		.and_then({
+			let config = Arc::clone(config);
+			let state = Arc::clone(state);
+			move |client: ClientAddr, authorization: Option<String>| {
+				let config = Arc::clone(&config);
+				let state = Arc::clone(&state);
+				async move {
+					if !config.web_ui {
+						return Err(warp::reject::not_found());
+					}
+					Ok(web_ui::page(&config, &state, client, authorization).await)
+				}
+			}
+		});
+
+	let routes = limits
+		.and(
+			update
+				.or(metrics)
+				.unify()
+				.or(checkip)
+				.unify()
+				.or(status)
+				.unify()
+				.or(web_ui)
+				.unify(),
+		)
+		.recover(limits::handle_rejection)
+		.recover(process::handle_rejection)
+		.map(Reply::into_response);
+	match &config.cors {
+		Some(cors) => routes
+			.with(cors::filter(cors))
+			.map(Reply::into_response)
+			.boxed(),
+		None => routes.boxed(),
+	}
 }
 
 #[tokio::main]
@@ -28,17 +191,52 @@ async fn main() -> Result<()> {
 
 	let args = Args::parse();
 
-	let config = Config::read(&args.config)?;
+	// These do not need a config file
+	match &args.command {
+		Some(Command::Bench(bench_args)) => return bench::run(bench_args).await,
+		Some(Command::GenSelfsigned(selfsigned_args)) => {
+			return selfsigned::generate(selfsigned_args)
+		}
+		_ => {}
+	}
 
-	let listen = config.listen;
-	let update = warp::get()
-		.and(warp::path("update"))
-		.and(warp::path::end())
-		.and(warp::query::<QueryParameters>())
-		.map(move |q: QueryParameters| update(&config, &q));
+	let config = Arc::new(Config::read(&args.config)?);
+	logging::set_redaction(config.log_redaction);
+	if let Some(log_file) = &config.log_file {
+		logging::set_log_file(log_file)
+			.wrap_err_with(|| format!("Cannot open log file `{}`", log_file.display()))?;
+		// Let logrotate tell us to open the file again after renaming it
+		let mut sigusr1 = signal(SignalKind::user_defined1())?;
+		tokio::spawn(async move {
+			while sigusr1.recv().await.is_some() {
+				match logging::reopen_log_file() {
+					Ok(()) => info!("Reopened the log file"),
+					Err(e) => error!("Cannot reopen the log file: {e}"),
+				}
+			}
+		});
+	}
+	if args.strict && !config.warnings.is_empty() {
+		return Err(eyre!(
+			"The config caused {} warnings, which are errors because of --strict",
+			config.warnings.len()
+		));
+	}
+
+	if matches!(args.command, Some(Command::Check)) {
+		info!("The config is valid");
+		return Ok(());
+	}
+
+	match &args.command {
+		Some(Command::HashPassword) => return password::hash_from_stdin(&config),
+		Some(Command::Oneshot(oneshot_args)) => return oneshot::run(&config, oneshot_args).await,
+		Some(Command::Refresh(refresh_args)) => return refresh::run(&config, refresh_args).await,
+		_ => {}
+	}
 
-	info!("Listening on {listen}");
-	warp::serve(update).run(listen).await;
+	let state = Arc::new(State::default());
 
-	Ok(())
+	let routes = routes(&config, &state);
+	server::run(&config.listen, &config.limits, routes).await
 }
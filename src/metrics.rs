@@ -0,0 +1,187 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Counters exposed in the Prometheus text format
+
+use crate::config::Metrics;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counts {
+	success: u64,
+	failure: u64,
+}
+
+impl Counts {
+	const fn record(&mut self, success: bool) {
+		if success {
+			self.success += 1;
+		} else {
+			self.failure += 1;
+		}
+	}
+}
+
+/// Runs of an update program for the domains of a zone
+#[derive(Clone, Copy, Debug, Default)]
+struct RunCounts {
+	counts: Counts,
+	duration: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+	total: Counts,
+	users: BTreeMap<String, Counts>,
+	/// By the name of the update program and the zone
+	backends: BTreeMap<(String, String), RunCounts>,
+}
+
+#[derive(Debug, Default)]
+pub struct Counters {
+	inner: Mutex<Inner>,
+}
+
+/// Escape a label value as required by the Prometheus text format
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+impl Counters {
+	/// Count a finished update request.
+	/// `user` is only given for users from the config, so clients cannot create arbitrary labels.
+	/// Only the first `max_user_labels` users get their own counters.
+	pub fn record(&self, config: &Metrics, user: Option<&str>, success: bool) {
+		let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		inner.total.record(success);
+		if let Some(user) = user {
+			if let Some(counts) = inner.users.get_mut(user) {
+				counts.record(success);
+			} else if inner.users.len() < config.max_user_labels {
+				inner
+					.users
+					.entry(user.to_string())
+					.or_default()
+					.record(success);
+			}
+		}
+	}
+
+	/// Count a run of the update program `backend` for `domains`, once for every zone of the domains.
+	/// Only the first `max_backend_labels` combinations of update program and zone get their own counters.
+	pub fn record_run<'a>(
+		&self,
+		config: &Metrics,
+		backend: &str,
+		domains: impl IntoIterator<Item = &'a str>,
+		duration: Duration,
+		success: bool,
+	) {
+		let zones: BTreeSet<_> = domains
+			.into_iter()
+			.map(|domain| config.zone(domain))
+			.collect();
+		let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		for zone in zones {
+			let key = (backend.to_string(), zone.to_string());
+			if !inner.backends.contains_key(&key)
+				&& inner.backends.len() >= config.max_backend_labels
+			{
+				continue;
+			}
+			let run_counts = inner.backends.entry(key).or_default();
+			run_counts.counts.record(success);
+			run_counts.duration += duration;
+		}
+		drop(inner);
+	}
+
+	pub fn render(&self, circuit_breaker_open: bool) -> String {
+		let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		let mut out = String::new();
+		let _ = writeln!(
+			out,
+			"# HELP dyndnsd_updates_total Update requests by result"
+		);
+		let _ = writeln!(out, "# TYPE dyndnsd_updates_total counter");
+		let _ = writeln!(
+			out,
+			"dyndnsd_updates_total{{result=\"success\"}} {}",
+			inner.total.success
+		);
+		let _ = writeln!(
+			out,
+			"dyndnsd_updates_total{{result=\"failure\"}} {}",
+			inner.total.failure
+		);
+		let _ = writeln!(
+			out,
+			"# HELP dyndnsd_user_updates_total Update requests by user and result"
+		);
+		let _ = writeln!(out, "# TYPE dyndnsd_user_updates_total counter");
+		for (user, counts) in &inner.users {
+			let user = escape(user);
+			let _ = writeln!(
+				out,
+				"dyndnsd_user_updates_total{{user=\"{user}\",result=\"success\"}} {}",
+				counts.success
+			);
+			let _ = writeln!(
+				out,
+				"dyndnsd_user_updates_total{{user=\"{user}\",result=\"failure\"}} {}",
+				counts.failure
+			);
+		}
+		let _ = writeln!(
+			out,
+			"# HELP dyndnsd_backend_runs_total Runs of the update programs by program, zone and result"
+		);
+		let _ = writeln!(out, "# TYPE dyndnsd_backend_runs_total counter");
+		for ((backend, zone), run_counts) in &inner.backends {
+			let labels = format!("backend=\"{}\",zone=\"{}\"", escape(backend), escape(zone));
+			let _ = writeln!(
+				out,
+				"dyndnsd_backend_runs_total{{{labels},result=\"success\"}} {}",
+				run_counts.counts.success
+			);
+			let _ = writeln!(
+				out,
+				"dyndnsd_backend_runs_total{{{labels},result=\"failure\"}} {}",
+				run_counts.counts.failure
+			);
+		}
+		let _ = writeln!(
+			out,
+			"# HELP dyndnsd_backend_duration_seconds Time the runs of the update programs took by program and zone"
+		);
+		let _ = writeln!(out, "# TYPE dyndnsd_backend_duration_seconds summary");
+		for ((backend, zone), run_counts) in &inner.backends {
+			let labels = format!("backend=\"{}\",zone=\"{}\"", escape(backend), escape(zone));
+			let _ = writeln!(
+				out,
+				"dyndnsd_backend_duration_seconds_sum{{{labels}}} {}",
+				run_counts.duration.as_secs_f64()
+			);
+			let _ = writeln!(
+				out,
+				"dyndnsd_backend_duration_seconds_count{{{labels}}} {}",
+				run_counts.counts.success + run_counts.counts.failure
+			);
+		}
+		drop(inner);
+		let _ = writeln!(out, "# HELP dyndnsd_circuit_breaker_open Whether the update program is currently not run because it failed too often");
+		let _ = writeln!(out, "# TYPE dyndnsd_circuit_breaker_open gauge");
+		let _ = writeln!(
+			out,
+			"dyndnsd_circuit_breaker_open {}",
+			u8::from(circuit_breaker_open)
+		);
+		out
+	}
+}
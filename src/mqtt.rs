@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Just enough of an MQTT 3.1.1 client to publish retained messages with QoS 0
+
+use crate::config::Mqtt;
+use crate::process::DomainUpdate;
+use crate::state::Addresses;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use log::{debug, error};
+use serde_json::json;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn push_length(packet: &mut Vec<u8>, mut length: usize) {
+	loop {
+		// Seven bits per byte, the highest bit says whether another byte follows
+		#[allow(clippy::cast_possible_truncation)] // The remainder always fits
+		let byte = (length % 128) as u8;
+		length /= 128;
+		if length == 0 {
+			packet.push(byte);
+			return;
+		}
+		packet.push(byte | 0x80);
+	}
+}
+
+fn push_string(packet: &mut Vec<u8>, string: &[u8]) -> Result<()> {
+	let length = u16::try_from(string.len()).wrap_err("String is too long for MQTT")?;
+	packet.extend_from_slice(&length.to_be_bytes());
+	packet.extend_from_slice(string);
+	Ok(())
+}
+
+fn packet(packet_type: u8, body: &[u8]) -> Vec<u8> {
+	let mut packet = vec![packet_type];
+	push_length(&mut packet, body.len());
+	packet.extend_from_slice(body);
+	packet
+}
+
+fn connect_packet(mqtt: &Mqtt) -> Result<Vec<u8>> {
+	let mut flags = 0x02; // Clean session
+	if mqtt.username.is_some() {
+		flags |= 0x80;
+	}
+	if mqtt.password.is_some() {
+		flags |= 0x40;
+	}
+	let mut body = Vec::new();
+	push_string(&mut body, b"MQTT")?;
+	body.push(4); // Protocol level of MQTT 3.1.1
+	body.push(flags);
+	body.extend_from_slice(&60u16.to_be_bytes()); // Keep alive in seconds
+	push_string(&mut body, mqtt.client_id.as_bytes())?;
+	if let Some(username) = &mqtt.username {
+		push_string(&mut body, username.as_bytes())?;
+	}
+	if let Some(password) = &mqtt.password {
+		push_string(&mut body, password)?;
+	}
+	Ok(packet(0x10, &body))
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Result<Vec<u8>> {
+	let mut body = Vec::new();
+	push_string(&mut body, topic.as_bytes())?;
+	body.extend_from_slice(payload);
+	// PUBLISH with QoS 0 and the retain flag
+	Ok(packet(0x31, &body))
+}
+
+async fn publish_all(mqtt: &Mqtt, messages: &[(String, Vec<u8>)]) -> Result<()> {
+	let mut stream = TcpStream::connect(&mqtt.server).await?;
+	stream.write_all(&connect_packet(mqtt)?).await?;
+	let mut connack = [0; 4];
+	stream.read_exact(&mut connack).await?;
+	if connack[..2] != [0x20, 0x02] {
+		return Err(eyre!("Server did not answer with CONNACK"));
+	}
+	if connack[3] != 0 {
+		return Err(eyre!(
+			"Server refused the connection with return code {}",
+			connack[3]
+		));
+	}
+	for (topic, payload) in messages {
+		debug!("Publishing to MQTT topic `{topic}`");
+		stream.write_all(&publish_packet(topic, payload)?).await?;
+	}
+	stream.write_all(&packet(0xe0, &[])).await?;
+	stream.flush().await?;
+	Ok(())
+}
+
+/// Publish a retained message with the current addresses of every domain whose addresses changed.
+/// This happens in the background and failures are only logged since the update itself already succeeded.
+pub fn publish_changes(mqtt: &Mqtt, changes: &[(&DomainUpdate<'_>, Addresses)]) {
+	let messages: Vec<_> = changes
+		.iter()
+		.filter(|(update, old)| {
+			(update.ipv4.is_some() && update.ipv4 != old.ipv4)
+				|| (update.ipv6.is_some() && update.ipv6 != old.ipv6)
+		})
+		.map(|(update, old)| {
+			let topic = mqtt.topic.replace("{domain}", update.domain);
+			let payload = json!({
+				"domain": update.domain,
+				"ttl": update.ttl,
+				"ipv4": update.ipv4.or(old.ipv4),
+				"ipv6": update.ipv6.or(old.ipv6),
+			});
+			(topic, payload.to_string().into_bytes())
+		})
+		.collect();
+	if messages.is_empty() {
+		return;
+	}
+	let mqtt = mqtt.clone();
+	tokio::spawn(async move {
+		match tokio::time::timeout(TIMEOUT, publish_all(&mqtt, &messages)).await {
+			Ok(Ok(())) => {}
+			Ok(Err(e)) => error!("Error publishing to MQTT server {}: {e}", mqtt.server),
+			Err(_) => error!("Publishing to MQTT server {} timed out", mqtt.server),
+		}
+	});
+}
@@ -0,0 +1,54 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::Notify;
+use crate::dns;
+use crate::process::DomainUpdate;
+use log::{debug, error, warn};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most specific configured zone containing `domain`
+fn zone_of<'a>(zones: &'a [String], domain: &str) -> Option<&'a str> {
+	let domain = domain.trim_end_matches('.');
+	zones
+		.iter()
+		.map(|zone| zone.trim_end_matches('.'))
+		.filter(|zone| {
+			domain == *zone
+				|| domain
+					.strip_suffix(zone)
+					.is_some_and(|subdomain| subdomain.ends_with('.'))
+		})
+		.max_by_key(|zone| zone.len())
+}
+
+/// Tell all secondary servers about the zones of the updated domains in the background.
+/// Failures are only logged, the secondaries still pick up the changes after the refresh interval.
+pub fn send(notify: &Notify, updates: &[DomainUpdate]) {
+	let zones: BTreeSet<_> = updates
+		.iter()
+		.filter_map(|update| zone_of(&notify.zones, update.domain))
+		.collect();
+	for zone in zones {
+		for &server in &notify.servers {
+			let zone = zone.to_string();
+			tokio::spawn(async move {
+				for attempt in 1..=ATTEMPTS {
+					match tokio::time::timeout(ATTEMPT_TIMEOUT, dns::notify(server, &zone)).await {
+						Ok(Ok(())) => {
+							debug!("Sent NOTIFY for zone {zone} to {server}");
+							return;
+						}
+						Ok(Err(e)) => warn!("Error sending NOTIFY for zone {zone} to {server}: {e}"),
+						Err(_) => warn!("NOTIFY for zone {zone} to {server} timed out (attempt {attempt} of {ATTEMPTS})"),
+					}
+				}
+				error!("Giving up sending NOTIFY for zone {zone} to {server}");
+			});
+		}
+	}
+}
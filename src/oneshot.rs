@@ -0,0 +1,87 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// A single update from the command line, handled like an update request without the HTTP server
+
+use crate::config::Config;
+use crate::process;
+use crate::state::State;
+use color_eyre::eyre::{eyre, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+	/// User whose domains are updated, the password is not needed
+	#[arg(long)]
+	user: String,
+	/// New IPv4 address
+	#[arg(long)]
+	ipv4: Option<String>,
+	/// New IPv6 address, combined with the suffix of every domain like in update requests
+	#[arg(long)]
+	ipv6: Option<String>,
+	/// IPv6 prefix of the LAN, e.g. `2001:db8:0:100::/56`
+	#[arg(long)]
+	ipv6lanprefix: Option<String>,
+	/// Length of the LAN prefix in `--ipv6`, for domains using the LAN prefix
+	#[arg(long)]
+	prefixlen: Option<u8>,
+	/// Domain to update, only for admins
+	#[arg(long)]
+	domain: Option<String>,
+	/// Comma separated list of domains of the user, only these are updated
+	#[arg(long)]
+	hostname: Option<String>,
+	/// Only wait for the records to become visible on the verification server
+	#[arg(long)]
+	verify: bool,
+	/// Show what would be done without running the update program
+	#[arg(long)]
+	dry_run: bool,
+}
+
+/// The query string of an equivalent update request
+fn query(args: &Args) -> Result<String> {
+	let mut pairs = vec![("user", args.user.as_str()), ("pass", "")];
+	let prefixlen = args.prefixlen.map(|len| len.to_string());
+	let optional = [
+		("ipv4", &args.ipv4),
+		("ipv6", &args.ipv6),
+		("ipv6lanprefix", &args.ipv6lanprefix),
+		("prefixlen", &prefixlen),
+		("domain", &args.domain),
+		("hostname", &args.hostname),
+	];
+	for (name, value) in optional {
+		if let Some(value) = value {
+			pairs.push((name, value));
+		}
+	}
+	if args.verify {
+		pairs.push(("verify", "1"));
+	}
+	if args.dry_run {
+		pairs.push(("dry_run", "1"));
+	}
+	Ok(serde_urlencoded::to_string(pairs)?)
+}
+
+/// Run the update and print the response body, failing if the update failed
+pub async fn run(config: &Arc<Config>, args: &Args) -> Result<()> {
+	let q = process::parse_query(&query(args)?, &HashMap::new())
+		.map_err(|e| eyre!("Invalid arguments: {e}"))?;
+	let state = Arc::new(State::default());
+	let response = process::oneshot(config, &state, &q).await;
+	state.hooks.wait().await;
+	state.shadow_runs.wait().await;
+	let status = response.status();
+	let body = warp::hyper::body::to_bytes(response.into_body()).await?;
+	let body = String::from_utf8_lossy(&body);
+	if !status.is_success() {
+		return Err(eyre!("Update failed with status {status}: {body}"));
+	}
+	writeln!(std::io::stdout(), "{body}")?;
+	Ok(())
+}
@@ -0,0 +1,37 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::Config;
+use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use color_eyre::eyre::{eyre, Result};
+use std::io::{BufRead, Write};
+
+/// Argon2 with the default parameters and the pepper as its secret, if there is one
+pub fn hasher(pepper: Option<&[u8]>) -> argon2::Result<Argon2<'_>> {
+	pepper.map_or_else(
+		|| Ok(Argon2::default()),
+		|pepper| {
+			Argon2::new_with_secret(
+				pepper,
+				Algorithm::default(),
+				Version::default(),
+				Params::default(),
+			)
+		},
+	)
+}
+
+/// Read a password from stdin and print its hash, using the pepper from the config
+pub fn hash_from_stdin(config: &Config) -> Result<()> {
+	let mut password = String::new();
+	std::io::stdin().lock().read_line(&mut password)?;
+	let password = password.trim_end_matches(['\r', '\n']);
+	let salt = SaltString::generate(&mut OsRng);
+	let argon2 = hasher(config.pepper.as_deref()).map_err(|e| eyre!("Invalid pepper: {e}"))?;
+	let hash = argon2
+		.hash_password(password.as_bytes(), &salt)
+		.map_err(|e| eyre!("Cannot hash password: {e}"))?;
+	writeln!(std::io::stdout(), "{hash}")?;
+	Ok(())
+}
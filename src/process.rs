@@ -1,52 +1,527 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use crate::config::{Config, User};
-use argon2::{password_hash::PasswordVerifier, Argon2};
+use crate::audit;
+use crate::config::{
+	ChangesFormat, Config, Domain, Features, Hooks, Ipv6PrefixLen, Quota, Responses, Role,
+	SourceCheck, UpdateMode, UpdateProgram, User, Verify,
+};
+use crate::dedupe;
+use crate::dns::{self, RecordType};
+use crate::events;
+use crate::geoip;
+use crate::hooks;
+use crate::logging::{self, Ip, Opt, Redaction, Username};
+use crate::mqtt;
+use crate::notify;
+use crate::password;
+use crate::request_id::{self, RequestId};
+use crate::server::ClientAddr;
+use crate::state::{Addresses, State};
+use crate::statsd;
+use argon2::password_hash::PasswordVerifier;
+use color_eyre::eyre::Result;
+use futures_util::future;
 use log::{debug, error, info, trace, warn};
+use serde::{de::Error as _, Deserializer};
 use serde_derive::Deserialize;
-use std::io::Write;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::process::{Command, Stdio};
-use warp::{http::StatusCode, Reply};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use warp::{
+	http::{HeaderValue, StatusCode},
+	reject::Reject,
+	reply::{Response, WithStatus},
+	Filter, Rejection, Reply,
+};
 
-#[derive(Deserialize)]
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const VERIFY_INTERVAL: Duration = Duration::from_millis(500);
+/// More runs of the shadow program are skipped, so a hanging one cannot pile up children
+const MAX_SHADOW_RUNS: usize = 16;
+
+/// Used for domains which admins update but which are not configured for any user
+static DEFAULT_DOMAIN: Domain = Domain {
+	ttl: 60,
+	ipv6prefixlen: Ipv6PrefixLen::Fixed(128),
+	ipv6suffix: Ipv6Addr::UNSPECIFIED,
+	static_ipv4: None,
+	static_ipv6: None,
+	hooks: Hooks {
+		post_update: Vec::new(),
+	},
+};
+
+#[derive(Default, Deserialize)]
 pub struct QueryParameters {
-	domain: Option<String>, // Ignored, we use the username to determine the list of domains to be updated
+	domain: Option<String>, // Only used by admins, everyone else updates the domains configured for them
 	user: String,
 	pass: String,
+	ipv4: Option<AddressParameter<Ipv4Addr>>,
+	ipv6: Option<AddressParameter<Ipv6Addr>>,
+	dualstack: Option<String>,
+	ipv6lanprefix: Option<Ipv6LanPrefix>,
+	prefixlen: Option<u8>,
+	verify: Option<String>,
+	dry_run: Option<String>,
+	system: Option<String>, // Sent by DynDNS2 clients, only `dyndns` is supported
+	hostname: Option<String>, // Comma separated list sent by DynDNS2 clients, only these domains are updated
+}
+
+/// The names of the fields of `QueryParameters`, which aliases can refer to
+pub const PARAMETER_NAMES: [&str; 12] = [
+	"domain",
+	"user",
+	"pass",
+	"ipv4",
+	"ipv6",
+	"dualstack",
+	"ipv6lanprefix",
+	"prefixlen",
+	"verify",
+	"dry_run",
+	"system",
+	"hostname",
+];
+
+#[derive(Debug)]
+struct InvalidQuery;
+impl Reject for InvalidQuery {}
+
+/// Parse the query string of an update request the same way `warp::query()` does,
+/// after renaming the parameters which have an alias configured
+pub fn parse_query<S: BuildHasher>(
+	query: &str,
+	aliases: &HashMap<String, String, S>,
+) -> Result<QueryParameters, serde_urlencoded::de::Error> {
+	if aliases.is_empty() {
+		return serde_urlencoded::from_str(query);
+	}
+	let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query)?;
+	let pairs: Vec<_> = pairs
+		.into_iter()
+		.map(|(name, value)| (aliases.get(&name).cloned().unwrap_or(name), value))
+		.collect();
+	let query = serde_urlencoded::to_string(pairs).map_err(serde::de::Error::custom)?;
+	serde_urlencoded::from_str(&query)
+}
+
+/// Extract the query parameters of an update request
+pub fn query(
+	config: Arc<Config>,
+) -> impl Filter<Extract = (QueryParameters,), Error = Rejection> + Clone {
+	warp::query::raw()
+		.or(warp::any().map(String::new))
+		.unify()
+		.and_then(move |query: String| {
+			let config = Arc::clone(&config);
+			async move { parse_or_reject(&config, &query) }
+		})
+}
+
+/// Extract the parameters of an update request sent as a form, like the one of the web UI.
+/// Unlike the query string, the body does not end up in the browser history and in the logs of proxies.
+#[must_use]
+pub fn form(
+	config: Arc<Config>,
+) -> impl Filter<Extract = (QueryParameters,), Error = Rejection> + Clone {
+	warp::body::bytes().and_then(move |body: warp::hyper::body::Bytes| {
+		let config = Arc::clone(&config);
+		async move { parse_or_reject(&config, &String::from_utf8_lossy(&body)) }
+	})
+}
+
+fn parse_or_reject(config: &Config, query: &str) -> Result<QueryParameters, Rejection> {
+	parse_query(query, &config.parameter_aliases).map_err(|e| {
+		warn!("Invalid query string: {e}");
+		warp::reject::custom(InvalidQuery)
+	})
+}
+
+/// Turn the rejection of invalid query strings into 400 Bad Request
+#[allow(clippy::unused_async)] // `recover()` needs a future
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+	if err.find::<InvalidQuery>().is_none() {
+		return Err(err);
+	}
+	Ok(warp::reply::with_status(
+		"Invalid query string".to_string(),
+		StatusCode::BAD_REQUEST,
+	))
+}
+
+fn is_enabled(flag: Option<&str>) -> bool {
+	flag.is_some_and(|v| v == "1" || v == "true")
+}
+
+/// An address in the query, or `auto` to use the source address of the request
+#[derive(Clone, Copy, Debug)]
+enum AddressParameter<A> {
+	Auto,
+	Address(A),
+}
+
+/// Parsing of the addresses in the query.
+/// The parsed addresses are the same no matter how they were written, e.g. `2001:DB8::1` and `2001:db8:0:0:0:0:0:1`,
+/// and are formatted in the canonical form of RFC 5952 in the update program input.
+trait QueryAddress: Sized {
+	fn parse_query(s: &str) -> Result<Self, String>;
+}
+
+impl QueryAddress for Ipv4Addr {
+	/// Also accepts IPv4-mapped IPv6 addresses like `::ffff:1.2.3.4`, which some clients report on dual-stack hosts
+	fn parse_query(s: &str) -> Result<Self, String> {
+		match s.parse::<IpAddr>().map(|address| address.to_canonical()) {
+			Ok(IpAddr::V4(ipv4)) => Ok(ipv4),
+			Ok(IpAddr::V6(_)) => Err(format!("`{s}` is not an IPv4 address")),
+			Err(e) => Err(e.to_string()),
+		}
+	}
+}
+
+impl QueryAddress for Ipv6Addr {
+	/// IPv4-mapped addresses are refused since an AAAA record with one is useless
+	fn parse_query(s: &str) -> Result<Self, String> {
+		let ipv6: Self = s
+			.parse()
+			.map_err(|e: std::net::AddrParseError| e.to_string())?;
+		if ipv6.to_ipv4_mapped().is_some() {
+			return Err(format!(
+				"`{s}` is an IPv4-mapped address, use the ipv4 parameter instead"
+			));
+		}
+		Ok(ipv6)
+	}
+}
+
+impl<'de, A: QueryAddress> serde::Deserialize<'de> for AddressParameter<A> {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		if s == "auto" {
+			Ok(Self::Auto)
+		} else {
+			A::parse_query(&s)
+				.map(Self::Address)
+				.map_err(D::Error::custom)
+		}
+	}
+}
+
+/// Replace `auto` with the source address of the request if it has the right address family
+fn resolve_addresses(
+	q: &QueryParameters,
+	client: ClientAddr,
+) -> Result<(Option<Ipv4Addr>, Option<Ipv6Addr>), WithStatus<String>> {
+	let source = client.0.map(|addr| addr.ip().to_canonical());
+	let ipv4 = match q.ipv4 {
+		None => None,
+		Some(AddressParameter::Address(ipv4)) => Some(ipv4),
+		Some(AddressParameter::Auto) => {
+			let Some(IpAddr::V4(ipv4)) = source else {
+				warn!("Cannot use the source address {client} for ipv4=auto");
+				return Err(warp::reply::with_status(
+					"Cannot determine IPv4 address".to_string(),
+					StatusCode::BAD_REQUEST,
+				));
+			};
+			Some(ipv4)
+		}
+	};
+	let ipv6 = match q.ipv6 {
+		None => None,
+		Some(AddressParameter::Address(ipv6)) => Some(ipv6),
+		Some(AddressParameter::Auto) => {
+			let Some(IpAddr::V6(ipv6)) = source else {
+				warn!("Cannot use the source address {client} for ipv6=auto");
+				return Err(warp::reply::with_status(
+					"Cannot determine IPv6 address".to_string(),
+					StatusCode::BAD_REQUEST,
+				));
+			};
+			Some(ipv6)
+		}
+	};
+	Ok((ipv4, ipv6))
+}
+
+/// Compare the IPv4 address to update with the source address of the request, if that is an IPv4 address
+fn check_ipv4_source(
+	check: SourceCheck,
 	ipv4: Option<Ipv4Addr>,
+	client: ClientAddr,
+) -> Result<(), WithStatus<String>> {
+	if check == SourceCheck::Off {
+		return Ok(());
+	}
+	let source = client.0.map(|addr| addr.ip().to_canonical());
+	let (Some(ipv4), Some(IpAddr::V4(source))) = (ipv4, source) else {
+		return Ok(());
+	};
+	if ipv4 == source {
+		return Ok(());
+	}
+	warn!(
+		"The IPv4 address {} differs from the source address {client} of the request",
+		Ip(ipv4.into())
+	);
+	if check == SourceCheck::Reject {
+		return Err(warp::reply::with_status(
+			"IPv4 address does not match the source address".to_string(),
+			StatusCode::FORBIDDEN,
+		));
+	}
+	Ok(())
+}
+
+impl<A: Into<IpAddr> + Copy> Display for AddressParameter<A> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Auto => write!(f, "auto"),
+			Self::Address(address) => write!(f, "{}", Ip((*address).into())),
+		}
+	}
+}
+
+/// A prefix like `2001:db8:0:100::/56`, as sent by FRITZ!Boxes in the `ipv6lanprefix` parameter
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Ipv6LanPrefix {
+	prefix: Ipv6Addr,
+	len: u8,
+}
+
+impl Ipv6LanPrefix {
+	fn new(prefix: Ipv6Addr, len: u8) -> Result<Self, String> {
+		if len > 128 {
+			return Err(format!("Prefix is longer than 128 bits: {len}"));
+		}
+		Ok(Self { prefix, len })
+	}
+}
+
+impl Display for Ipv6LanPrefix {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let prefix = Ip(self.prefix.into());
+		if logging::redaction() == Redaction::None {
+			write!(f, "{prefix}/{}", self.len)
+		} else {
+			write!(f, "{prefix}")
+		}
+	}
+}
+
+impl TryFrom<String> for Ipv6LanPrefix {
+	type Error = String;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		let (prefix, len) = s
+			.split_once('/')
+			.ok_or_else(|| format!("Missing prefix length in `{s}`"))?;
+		let prefix = prefix.parse().map_err(|e| format!("{e}"))?;
+		let len = len.parse().map_err(|e| format!("{e}"))?;
+		Self::new(prefix, len)
+	}
+}
+
+/// The LAN prefix is either sent directly or as the `ipv6` parameter together with its length
+fn lan_prefix(
+	q: &QueryParameters,
 	ipv6: Option<Ipv6Addr>,
-	dualstack: Option<String>,
-	ipv6lanprefix: Option<String>,
+) -> Result<Option<Ipv6LanPrefix>, WithStatus<String>> {
+	if let Some(lan_prefix) = q.ipv6lanprefix {
+		return Ok(Some(lan_prefix));
+	}
+	let (Some(prefix), Some(len)) = (ipv6, q.prefixlen) else {
+		return Ok(None);
+	};
+	Ipv6LanPrefix::new(prefix, len).map(Some).map_err(|e| {
+		warn!("Invalid prefixlen parameter: {e}");
+		warp::reply::with_status(e, StatusCode::BAD_REQUEST)
+	})
 }
 
-fn splice_ipv6_addrs(prefixlen: u8, prefix: Ipv6Addr, suffix: Ipv6Addr) -> Ipv6Addr {
+/// Combine the first `prefixlen` bits of `prefix` with the remaining bits of `suffix`
+#[must_use]
+pub fn splice_ipv6_addrs(prefixlen: u8, prefix: Ipv6Addr, suffix: Ipv6Addr) -> Ipv6Addr {
 	let prefix_bits = u128::from(prefix);
 	let suffix_bits = u128::from(suffix);
-	let hostlen = 128 - prefixlen;
-	let suffix_mask = 2u128.pow(u32::from(hostlen)) - 1;
+	// Shifting by 128 bits or more is not possible, the suffix has no bits left in that case
+	let suffix_mask = u128::MAX.checked_shr(u32::from(prefixlen)).unwrap_or(0);
 	let masked_prefix = prefix_bits & !suffix_mask;
 	let masked_suffix = suffix_bits & suffix_mask;
 	Ipv6Addr::from(masked_prefix | masked_suffix)
 }
 
-fn build_command_string(config: &Config, user: &User, q: &QueryParameters) -> String {
+/// The new addresses of a single domain, computed from the query parameters and the domain config
+#[derive(Clone, Copy, Debug)]
+pub struct DomainUpdate<'a> {
+	pub domain: &'a str,
+	pub ttl: u32,
+	pub ipv4: Option<Ipv4Addr>,
+	pub ipv6: Option<Ipv6Addr>,
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+	!domain.is_empty()
+		&& domain
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_')
+}
+
+/// The settings of a domain updated by an admin.
+/// Prefer the settings of the admin, then those of the user owning the domain.
+fn admin_domain_settings<'a>(config: &'a Config, admin: &'a User, domain: &str) -> &'a Domain {
+	admin
+		.domains
+		.get(domain)
+		.or_else(|| {
+			config
+				.users
+				.values()
+				.find_map(|user| user.domains.get(domain))
+		})
+		.unwrap_or(&DEFAULT_DOMAIN)
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+	list.split(',')
+		.map(str::trim)
+		.filter(|domain| !domain.is_empty())
+}
+
+/// The domains to update, usually the ones configured for the user.
+/// Admins may instead name any domains in the `domain` parameter, separated by commas.
+fn select_domains<'a>(
+	config: &'a Config,
+	user: &'a User,
+	q: &'a QueryParameters,
+) -> Result<Vec<(&'a str, &'a Domain)>, WithStatus<String>> {
+	if let Some(hostnames) = &q.hostname {
+		// Unknown hostnames are answered with `nohost` instead of failing the whole request
+		return Ok(split_list(hostnames)
+			.filter_map(|hostname| {
+				let props = user.domains.get(hostname).or_else(|| {
+					(user.role == Role::Admin && is_valid_domain(hostname))
+						.then(|| admin_domain_settings(config, user, hostname))
+				});
+				if props.is_none() {
+					warn!(
+						"User {} does not own hostname `{hostname}`",
+						Username(&q.user)
+					);
+				}
+				Some((hostname, props?))
+			})
+			.collect());
+	}
+	let (Some(requested), Role::Admin) = (&q.domain, user.role) else {
+		let mut domains: Vec<_> = user
+			.domains
+			.iter()
+			.map(|(domain, props)| (domain.as_str(), props))
+			.collect();
+		// Sorted so that `quota.max_domains` always selects the same domains
+		domains.sort_unstable_by_key(|&(domain, _)| domain);
+		return Ok(domains);
+	};
+	split_list(requested)
+		.map(|domain| {
+			if !is_valid_domain(domain) {
+				warn!("Invalid domain `{domain}`");
+				return Err(warp::reply::with_status(
+					"Invalid domain".to_string(),
+					StatusCode::BAD_REQUEST,
+				));
+			}
+			Ok((domain, admin_domain_settings(config, user, domain)))
+		})
+		.collect()
+}
+
+/// The domains of the other users of the same site which use the LAN prefix.
+/// They share the uplink and thus the delegated prefix with the user sending it.
+fn site_domains<'a>(config: &'a Config, username: &str, user: &User) -> Vec<(&'a str, &'a Domain)> {
+	let Some(site) = &user.site else {
+		return Vec::new();
+	};
+	let mut domains: Vec<_> = config
+		.users
+		.iter()
+		.filter(|&(name, member)| name != username && member.site.as_ref() == Some(site))
+		.flat_map(|(_, member)| &member.domains)
+		.filter(|(domain, props)| {
+			props.ipv6prefixlen == Ipv6PrefixLen::Lan && !user.domains.contains_key(*domain)
+		})
+		.map(|(domain, props)| (domain.as_str(), props))
+		.collect();
+	domains.sort_unstable_by_key(|&(domain, _)| domain);
+	domains.dedup_by_key(|&mut (domain, _)| domain);
+	domains
+}
+
+fn plan_updates<'a>(
+	domains: &[(&'a str, &'a Domain)],
+	ipv4: Option<Ipv4Addr>,
+	ipv6: Option<Ipv6Addr>,
+	lan_prefix: Option<Ipv6LanPrefix>,
+) -> Vec<DomainUpdate<'a>> {
+	domains
+		.iter()
+		.map(|&(domain, props)| {
+			trace!("Domain: {domain:?} {props:?}");
+			let ipv4 = props.static_ipv4.or(ipv4);
+			let ipv6 = props.static_ipv6.or_else(|| match props.ipv6prefixlen {
+				Ipv6PrefixLen::Fixed(0) => {
+					if ipv6.is_some() {
+						warn!("IPv6 prefix length for domain {domain} is zero, ignoring update to IPv6 address");
+					}
+					None
+				}
+				Ipv6PrefixLen::Fixed(prefixlen) => ipv6
+					.map(|prefix| splice_ipv6_addrs(prefixlen, prefix, props.ipv6suffix)),
+				Ipv6PrefixLen::Lan => {
+					if lan_prefix.is_none() && ipv6.is_some() {
+						warn!("Domain {domain} uses the LAN prefix but the request contains neither ipv6lanprefix nor prefixlen, ignoring update to IPv6 address");
+					}
+					lan_prefix.map(|lan_prefix| {
+						splice_ipv6_addrs(lan_prefix.len, lan_prefix.prefix, props.ipv6suffix)
+					})
+				}
+			});
+			DomainUpdate {
+				domain,
+				ttl: props.ttl,
+				ipv4,
+				ipv6,
+			}
+		})
+		.collect()
+}
+
+// The templates use `{placeholder}` syntax, not format strings
+#[allow(clippy::literal_string_with_formatting_args)]
+fn build_command_string(update_program: &UpdateProgram, updates: &[DomainUpdate]) -> String {
 	// TODO: stream stdin to the process instead of building a string and then pushing it all at once
 	let mut command = String::new();
-	if let Some(initial_stdin) = &config.update_program.initial_stdin {
+	if let Some(initial_stdin) = &update_program.initial_stdin {
 		command.push_str(initial_stdin);
 	}
-	let domains = &user.domains;
-	for (domain, props) in domains {
-		trace!("Domain: {domain:?} {props:?}");
-		let ttl = &props.ttl.to_string();
-		if let Some(ipv4) = q.ipv4 {
+	for update in updates {
+		let domain = update.domain;
+		let ttl = &update.ttl.to_string();
+		if let (Some(ipv4), Some(family)) = (update.ipv4, &update_program.ipv4) {
 			let ipv4 = &ipv4.to_string();
 			command.push_str(
-				config
-					.update_program
-					.ipv4
+				family
 					.stdin
 					.replace("{domain}", domain)
 					.replace("{ttl}", ttl)
@@ -54,89 +529,1091 @@ fn build_command_string(config: &Config, user: &User, q: &QueryParameters) -> St
 					.as_str(),
 			);
 		}
-		if let Some(prefix) = q.ipv6 {
-			if props.ipv6prefixlen == 0 {
-				warn!("IPv6 prefix length for domain {domain} is zero, ignoring update to IPv6 address");
-			} else {
-				let assembled_addr =
-					splice_ipv6_addrs(props.ipv6prefixlen, prefix, props.ipv6suffix);
-				let ipv6 = &assembled_addr.to_string();
-				command.push_str(
-					config
-						.update_program
-						.ipv6
-						.stdin
-						.replace("{domain}", domain)
-						.replace("{ttl}", ttl)
-						.replace("{ipv6}", ipv6)
-						.as_str(),
-				);
-			};
+		if let (Some(ipv6), Some(family)) = (update.ipv6, &update_program.ipv6) {
+			let ipv6 = &ipv6.to_string();
+			command.push_str(
+				family
+					.stdin
+					.replace("{domain}", domain)
+					.replace("{ttl}", ttl)
+					.replace("{ipv6}", ipv6)
+					.as_str(),
+			);
 		}
-		command.push_str(config.update_program.stdin_per_zone_update.as_str());
+		command.push_str(update_program.stdin_per_zone_update.as_str());
+	}
+	command.push_str(update_program.final_stdin.as_str());
+	// The commands contain the addresses
+	if logging::redaction() == Redaction::None {
+		debug!("Commands for update program:\n{command}");
 	}
-	command.push_str(config.update_program.final_stdin.as_str());
-	debug!("Commands for update program:\n{command}");
 	command
 }
 
-pub fn update(config: &Config, q: &QueryParameters) -> Result<impl Reply, impl Reply> {
-	info!("Incoming request from user `{}`", &q.user);
-	debug!("domain: {:?}, user: {:?}, pass: <redacted>, ipv4: {:?}, ipv6: {:?}, dualstack: {:?}, ipv6lanprefix: {:?}", &q.domain, &q.user, &q.ipv4, &q.ipv6, &q.dualstack, &q.ipv6lanprefix);
+/// Fill in the placeholders of a response template.
+/// The domains and addresses are only known for successful updates and are empty otherwise.
+#[allow(clippy::literal_string_with_formatting_args)]
+fn render_response(
+	template: &str,
+	status: StatusCode,
+	message: &str,
+	updates: &[DomainUpdate],
+) -> String {
+	let domains: Vec<_> = updates.iter().map(|update| update.domain).collect();
+	let ipv4 = updates
+		.iter()
+		.find_map(|update| update.ipv4)
+		.map(|ipv4| ipv4.to_string());
+	let ipv6 = updates
+		.iter()
+		.find_map(|update| update.ipv6)
+		.map(|ipv6| ipv6.to_string());
+	template
+		.replace("{status}", status.as_str())
+		.replace("{message}", message)
+		.replace("{domain}", &domains.join(","))
+		.replace("{ipv4}", ipv4.as_deref().unwrap_or_default())
+		.replace("{ipv6}", ipv6.as_deref().unwrap_or_default())
+}
+
+/// Clients of the `DynDNS2` protocol expect one line per hostname, otherwise the body is just `ok`
+fn default_success_body(q: &QueryParameters, updates: &[DomainUpdate]) -> String {
+	let Some(hostnames) = &q.hostname else {
+		return "ok".to_string();
+	};
+	let lines: Vec<_> = split_list(hostnames)
+		.map(|hostname| {
+			let Some(update) = updates.iter().find(|update| update.domain == hostname) else {
+				return "nohost".to_string();
+			};
+			let address = update
+				.ipv4
+				.map(IpAddr::from)
+				.or_else(|| update.ipv6.map(IpAddr::from));
+			address.map_or_else(|| "good".to_string(), |address| format!("good {address}"))
+		})
+		.collect();
+	lines.join("\n")
+}
+
+/// Describe how the addresses of the domains changed, for `responses.changes`.
+/// Addresses which were not sent to the update program since the start of dyndnsd are unknown.
+fn describe_changes(
+	format: ChangesFormat,
+	message: String,
+	changes: &[(&DomainUpdate, Addresses)],
+) -> String {
+	match format {
+		ChangesFormat::Text => {
+			let mut lines = vec![message];
+			for (update, old) in changes {
+				let families = [
+					(
+						"A",
+						update.ipv4.map(IpAddr::from),
+						old.ipv4.map(IpAddr::from),
+					),
+					(
+						"AAAA",
+						update.ipv6.map(IpAddr::from),
+						old.ipv6.map(IpAddr::from),
+					),
+				];
+				for (record_type, new, old) in families {
+					if let Some(new) = new {
+						let old = old.map_or_else(|| "unknown".to_string(), |old| old.to_string());
+						lines.push(format!("{} {record_type} {old} -> {new}", update.domain));
+					}
+				}
+			}
+			lines.join("\n")
+		}
+		ChangesFormat::Json => {
+			let domains: Vec<_> = changes
+				.iter()
+				.map(|(update, old)| {
+					serde_json::json!({
+						"domain": update.domain,
+						"ipv4": update.ipv4.map(|new| serde_json::json!({ "old": old.ipv4, "new": new })),
+						"ipv6": update.ipv6.map(|new| serde_json::json!({ "old": old.ipv6, "new": new })),
+					})
+				})
+				.collect();
+			serde_json::json!({ "result": message, "domains": domains }).to_string()
+		}
+	}
+}
+
+fn success(
+	responses: &Responses,
+	q: &QueryParameters,
+	updates: &[DomainUpdate],
+	changes: &[(&DomainUpdate, Addresses)],
+) -> WithStatus<String> {
+	let mut message = default_success_body(q, updates);
+	if let Some(format) = responses.changes {
+		message = describe_changes(format, message, changes);
+	}
+	let body = responses.success.as_ref().map_or_else(
+		|| message.clone(),
+		|template| render_response(template, StatusCode::OK, &message, updates),
+	);
+	warp::reply::with_status(body, StatusCode::OK)
+}
+
+pub async fn update(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+	q: &QueryParameters,
+	client: ClientAddr,
+) -> Response {
+	let id = RequestId::new();
+	let start = Instant::now();
+	let respond = Box::pin(respond(config, state, q, client));
+	let source_ip = client.0.map(|addr| addr.ip().to_canonical());
+	let respond = logging::scope(&q.user, source_ip, events::collect(respond));
+	let (mut response, domains) = request_id::scope(id, respond).await;
+	// Internal errors are only described in the log, the client gets the ID to find them there
+	if response.status() == StatusCode::INTERNAL_SERVER_ERROR {
+		*response.body_mut() = format!("Internal server error, request ID {id}").into();
+	}
+	if let Some(template) = &config.responses.error {
+		if !response.status().is_success() {
+			let (parts, body) = response.into_parts();
+			let message = warp::hyper::body::to_bytes(body).await.unwrap_or_default();
+			let message = String::from_utf8_lossy(&message);
+			let body = render_response(template, parts.status, &message, &[]);
+			response = Response::from_parts(parts, body.into());
+		}
+	}
+	if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+		response.headers_mut().insert("X-Request-Id", value);
+	}
+	// A template can turn the JSON into anything, so it is only JSON without one
+	if response.status().is_success()
+		&& config.responses.changes == Some(ChangesFormat::Json)
+		&& config.responses.success.is_none()
+	{
+		response
+			.headers_mut()
+			.insert("Content-Type", HeaderValue::from_static("application/json"));
+	}
+	if let Some(metrics) = &config.metrics {
+		let user = config
+			.users
+			.contains_key(&q.user)
+			.then_some(q.user.as_str());
+		state
+			.metrics
+			.record(metrics, user, response.status().is_success());
+	}
+	if let Some(events_file) = &config.events_file {
+		let status = response.status();
+		state
+			.events
+			.write(events_file, id, &q.user, client, status, &domains);
+	}
+	if let Some(statsd) = &config.statsd {
+		statsd::record(statsd, response.status().is_success(), start.elapsed());
+	}
+	response
+}
+
+async fn respond(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+	q: &QueryParameters,
+	client: ClientAddr,
+) -> Response {
+	let timeout = config.limits.request_timeout;
+	let Ok(response) = tokio::time::timeout(timeout, handle_update(config, state, q, client)).await
+	else {
+		error!("Request took longer than {timeout:?}, aborting");
+		return warp::reply::with_status("Timed out".to_string(), StatusCode::GATEWAY_TIMEOUT)
+			.into_response();
+	};
+	response
+}
+
+/// The response while the circuit breaker is open, telling the client when to retry
+fn breaker_open(config: &Config, state: &State) -> Option<Response> {
+	config.circuit_breaker.as_ref()?;
+	let retry_after = state.breaker.retry_after()?;
+	warn!("Circuit breaker is open, rejecting request");
+	let retry_after = retry_after.as_secs() + 1;
+	Some(
+		warp::reply::with_header(
+			warp::reply::with_status(
+				"Service unavailable".to_string(),
+				StatusCode::SERVICE_UNAVAILABLE,
+			),
+			"Retry-After",
+			retry_after.to_string(),
+		)
+		.into_response(),
+	)
+}
+
+/// Check the password of a user without blocking the async runtime
+pub async fn authenticate<'a>(
+	config: &'a Config,
+	state: &State,
+	client: ClientAddr,
+	username: &str,
+	pass: &str,
+) -> Result<&'a User, WithStatus<String>> {
+	let not_authorized =
+		|| warp::reply::with_status("Not authorized".to_string(), StatusCode::FORBIDDEN);
+	let Some(user) = config.users.get(username) else {
+		warn!("User {} does not exist.", Username(username));
+		return Err(not_authorized());
+	};
+
+	let ip = client.0.map(|addr| addr.ip().to_canonical());
+	let window = config.auth_failure_window;
+	if state.auth_failures.contains(ip, username, pass, window) {
+		warn!("Error verifying password: the same password was wrong less than {window:?} ago");
+		return Err(not_authorized());
+	}
 
-	let Some(user) = config.users.get(&q.user) else {
-		warn!("User {} does not exist.", q.user);
+	// Password hashing is CPU intensive, don't block the async runtime (and the request timeout) with it
+	let hash = user.hash.clone();
+	let attempt = pass.to_string();
+	let pepper = config.pepper.clone();
+	let verification = tokio::task::spawn_blocking(move || {
+		let argon2 = password::hasher(pepper.as_deref())
+			.map_err(|_| argon2::password_hash::Error::Crypto)?;
+		argon2.verify_password(attempt.as_bytes(), &hash.password_hash())
+	})
+	.await;
+	match verification {
+		Ok(Ok(())) => {}
+		Ok(Err(e)) => {
+			warn!("Error verifying password: {e}");
+			if !window.is_zero() {
+				state.auth_failures.insert(ip, username, pass, window);
+			}
+			return Err(not_authorized());
+		}
+		Err(e) => {
+			error!(
+				"Error waiting for the password verification of request {}: {e}",
+				request_id::current()
+			);
+			return Err(warp::reply::with_status(
+				"ERROR".to_string(),
+				StatusCode::INTERNAL_SERVER_ERROR,
+			));
+		}
+	}
+	info!("Authentication successful");
+	Ok(user)
+}
+
+async fn handle_update(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+	q: &QueryParameters,
+	client: ClientAddr,
+) -> Response {
+	info!(
+		"Incoming request {} from user `{}` at {client}",
+		request_id::current(),
+		Username(&q.user)
+	);
+	debug!("domain: {:?}, user: {}, pass: <redacted>, ipv4: {}, ipv6: {}, dualstack: {:?}, ipv6lanprefix: {}", &q.domain, Username(&q.user), Opt(&q.ipv4), Opt(&q.ipv6), &q.dualstack, Opt(&q.ipv6lanprefix));
+
+	let user = match authenticate(config, state, client, &q.user, &q.pass).await {
+		Ok(user) => user,
+		Err(reply) => return reply.into_response(),
+	};
+	if let Some(geoip) = &config.geoip {
+		if let Err(reply) = geoip::check(geoip, &q.user, user, client) {
+			return reply.into_response();
+		}
+	}
+	// Only tell authenticated users about the state of the update program
+	if let Some(response) = breaker_open(config, state) {
+		return response;
+	}
+	let (Ok(reply) | Err(reply)) = update_user(config, state, q, client, user).await;
+	reply.into_response()
+}
+
+/// Run an update of `q.user` without checking the password, for the `oneshot` subcommand
+pub async fn oneshot(config: &Arc<Config>, state: &Arc<State>, q: &QueryParameters) -> Response {
+	request_id::scope(RequestId::new(), async {
+		info!("One-shot update of user `{}`", Username(&q.user));
+		let Some(user) = config.users.get(&q.user) else {
+			error!("User {} does not exist.", Username(&q.user));
+			return warp::reply::with_status(
+				"User does not exist".to_string(),
+				StatusCode::NOT_FOUND,
+			)
+			.into_response();
+		};
+		let update = Box::pin(update_user(config, state, q, ClientAddr(None), user));
+		let (Ok(reply) | Err(reply)) = logging::scope(&q.user, None, update).await;
+		reply.into_response()
+	})
+	.await
+}
+
+/// Run the update program again with the addresses last sent for the domains of `username`,
+/// for the `refresh` subcommand. Domains without known addresses are skipped.
+pub async fn refresh(config: &Arc<Config>, state: &Arc<State>, username: &str) -> Response {
+	request_id::scope(RequestId::new(), async {
+		info!("Refreshing the domains of user `{}`", Username(username));
+		let Some(user) = config.users.get(username) else {
+			error!("User {} does not exist.", Username(username));
+			return warp::reply::with_status(
+				"User does not exist".to_string(),
+				StatusCode::NOT_FOUND,
+			)
+			.into_response();
+		};
+		let mut domains: Vec<_> = user
+			.domains
+			.iter()
+			.filter(|(domain, _)| {
+				let addresses = state.addresses(domain);
+				if addresses.ipv4.is_none() && addresses.ipv6.is_none() {
+					warn!("No known addresses for domain {domain}, skipping it");
+					return false;
+				}
+				true
+			})
+			.map(|(domain, props)| (domain.as_str(), props))
+			.collect();
+		domains.sort_unstable_by_key(|&(domain, _)| domain);
+		if domains.is_empty() {
+			error!("None of the domains has known addresses");
+			return warp::reply::with_status(
+				"No known addresses".to_string(),
+				StatusCode::NOT_FOUND,
+			)
+			.into_response();
+		}
+		let mut updates: Vec<_> = domains
+			.iter()
+			.map(|&(domain, props)| {
+				let addresses = state.addresses(domain);
+				DomainUpdate {
+					domain,
+					ttl: props.ttl,
+					ipv4: addresses.ipv4,
+					ipv6: addresses.ipv6,
+				}
+			})
+			.collect();
+		apply_min_ttl(&user.quota, &mut updates);
+		let q = QueryParameters {
+			user: username.to_string(),
+			..QueryParameters::default()
+		};
+		let client = ClientAddr(None);
+		let apply = Box::pin(apply_updates(
+			config, state, &q, client, None, &domains, &updates,
+		));
+		let (Ok(reply) | Err(reply)) = logging::scope(username, None, apply).await;
+		reply.into_response()
+	})
+	.await
+}
+
+/// Everything after the authentication of an update request
+async fn update_user(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+	q: &QueryParameters,
+	client: ClientAddr,
+	user: &User,
+) -> Result<WithStatus<String>, WithStatus<String>> {
+	check_allowed_parameters(config.features, user, q)?;
+
+	if q.system.as_deref().is_some_and(|system| system != "dyndns") {
+		warn!("Unsupported DynDNS2 system");
 		return Err(warp::reply::with_status(
-			"Not authorized".to_string(),
-			StatusCode::FORBIDDEN,
+			"badsys".to_string(),
+			StatusCode::BAD_REQUEST,
 		));
+	}
+
+	let verify = if is_enabled(q.verify.as_deref()) {
+		let Some(verify) = &config.verify else {
+			warn!("Client asked for verification but it is not configured");
+			return Err(warp::reply::with_status(
+				"Verification not configured".to_string(),
+				StatusCode::BAD_REQUEST,
+			));
+		};
+		Some(verify)
+	} else {
+		None
+	};
+
+	let (ipv4, ipv6) = resolve_addresses(q, client)?;
+	check_ipv4_source(config.ipv4_source_check, ipv4, client)?;
+	let lan_prefix = lan_prefix(q, ipv6)?;
+	let mut domains = select_domains(config, user, q)?;
+	let mut updates = plan_updates(&domains, ipv4, ipv6, lan_prefix);
+	if lan_prefix.is_some() {
+		let site_domains = site_domains(config, &q.user, user);
+		if !site_domains.is_empty() {
+			info!(
+				"Applying the LAN prefix to {} domains of other users of the same site",
+				site_domains.len()
+			);
+		}
+		// Only the prefix is shared, everything else belongs to the other users
+		updates.extend(
+			plan_updates(&site_domains, None, None, lan_prefix)
+				.into_iter()
+				.map(|update| DomainUpdate {
+					ipv4: None,
+					..update
+				}),
+		);
+		domains.extend(site_domains);
+	}
+	// The domains of the same site count towards the quota of the user sending the prefix
+	apply_quota(&user.quota, &q.user, &mut domains, &mut updates);
+	apply_min_ttl(&user.quota, &mut updates);
+
+	logging::set_domains(domains.iter().map(|&(domain, _)| domain));
+
+	if q.hostname.is_some() && updates.is_empty() {
+		info!("None of the hostnames can be updated");
+		return Ok(success(&config.responses, q, &updates, &[]));
+	}
+
+	if is_enabled(q.dry_run.as_deref()) {
+		info!("Dry run, not running the update program");
+		// Only for the debug log, to show what would have been run
+		build_command_string(&config.update_program, &updates);
+		return Ok(success(&config.responses, q, &updates, &[]));
+	}
+
+	if config.dedupe_window.is_zero() {
+		return apply_updates(config, state, q, client, verify, &domains, &updates).await;
+	}
+	let key = dedupe::Key::new(&q.user, &updates, verify.is_some());
+	let cached = state.dedupe.get(key, config.dedupe_window);
+	let mut is_duplicate = true;
+	let (status, body, event_domains) = cached
+		.get_or_init(|| async {
+			is_duplicate = false;
+			let apply = Box::pin(apply_updates(
+				config, state, q, client, verify, &domains, &updates,
+			));
+			let (Ok(reply) | Err(reply), event_domains) = events::collect(apply).await;
+			let response = reply.into_response();
+			let status = response.status();
+			let body = warp::hyper::body::to_bytes(response.into_body())
+				.await
+				.unwrap_or_default();
+			(
+				status,
+				String::from_utf8_lossy(&body).into_owned(),
+				event_domains,
+			)
+		})
+		.await
+		.clone();
+	if is_duplicate {
+		info!("Duplicate request, answering with the reply to the previous one");
+	}
+	// The event of a duplicate shows the domains of the request it got the reply of
+	events::add_domains(&event_domains);
+	let reply = warp::reply::with_status(body, status);
+	if status.is_success() {
+		Ok(reply)
+	} else {
+		Err(reply)
+	}
+}
+
+/// Drop the domains exceeding `quota.max_domains` together with their updates
+fn apply_quota(
+	quota: &Quota,
+	username: &str,
+	domains: &mut Vec<(&str, &Domain)>,
+	updates: &mut Vec<DomainUpdate>,
+) {
+	let Some(max_domains) = quota.max_domains else {
+		return;
 	};
+	if domains.len() > max_domains.get() {
+		warn!(
+			"Request of user {} contains {} domains, only updating the first {max_domains}",
+			Username(username),
+			domains.len()
+		);
+		domains.truncate(max_domains.get());
+		updates.truncate(max_domains.get());
+	}
+}
+
+/// Raise the TTLs below `quota.min_ttl`
+fn apply_min_ttl(quota: &Quota, updates: &mut [DomainUpdate]) {
+	if let Some(min_ttl) = quota.min_ttl {
+		for update in updates {
+			update.ttl = update.ttl.max(min_ttl);
+		}
+	}
+}
 
-	if let Err(e) = Argon2::default().verify_password(q.pass.as_bytes(), &user.hash) {
-		warn!("Error verifying password: {e}");
+/// Count the update request towards `quota.max_updates_per_day` and refuse it if there were too many
+fn check_daily_quota(
+	config: &Config,
+	state: &State,
+	username: &str,
+) -> Result<(), WithStatus<String>> {
+	let Some(max_updates_per_day) = config
+		.users
+		.get(username)
+		.and_then(|user| user.quota.max_updates_per_day)
+	else {
+		return Ok(());
+	};
+	let retry_after = state.daily_updates.check(
+		username.to_string(),
+		max_updates_per_day.get(),
+		Duration::from_secs(SECS_PER_DAY),
+	);
+	if let Some(retry_after) = retry_after {
+		warn!(
+			"User {} exceeded the quota of {max_updates_per_day} updates per day, try again in {}s",
+			Username(username),
+			retry_after.as_secs()
+		);
 		return Err(warp::reply::with_status(
-			"Not authorized".to_string(),
-			StatusCode::FORBIDDEN,
+			"Quota exceeded".to_string(),
+			StatusCode::TOO_MANY_REQUESTS,
 		));
+	}
+	Ok(())
+}
+
+/// Run the update program and everything that follows a successful update
+async fn apply_updates(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+	q: &QueryParameters,
+	client: ClientAddr,
+	verify: Option<&Verify>,
+	domains: &[(&str, &Domain)],
+	updates: &[DomainUpdate<'_>],
+) -> Result<WithStatus<String>, WithStatus<String>> {
+	check_daily_quota(config, state, &q.user)?;
+	let breaker_run = config
+		.circuit_breaker
+		.as_ref()
+		.map(|circuit_breaker| state.breaker.start(circuit_breaker));
+	let shadow = config
+		.shadow_program
+		.is_some()
+		.then(|| spawn_shadow(config, state, &q.user, client, updates));
+	let (succeeded, result) =
+		run_update_programs(config, state, &config.update_program, updates, false).await;
+	if let Some(shadow) = shadow {
+		// The shadow program may still be running, it compares the results when it finishes
+		let _ = shadow.send(succeeded.clone());
+	}
+	events::record_domains(updates, &succeeded);
+	let source = client.0.map(|addr| addr.ip().to_canonical());
+	for (update, &success) in updates.iter().zip(&succeeded) {
+		state.record_history(update, success, source, config.history_length);
+	}
+	if let Some(breaker_run) = breaker_run {
+		breaker_run.finish(result.is_ok());
+	}
+	// In per-domain mode, the domains which were updated successfully are handled as usual even if others failed
+	let (updates, domains): (Vec<_>, Vec<_>) = updates
+		.iter()
+		.copied()
+		.zip(domains.iter().copied())
+		.zip(succeeded)
+		.filter_map(|(pair, succeeded)| succeeded.then_some(pair))
+		.unzip();
+	if updates.is_empty() {
+		return result.map(|()| success(&config.responses, q, &[], &[]));
+	}
+
+	let changes: Vec<_> = updates
+		.iter()
+		.map(|update| (update, state.record(update)))
+		.collect();
+	if let Some(audit_log) = &config.audit_log {
+		audit::write(audit_log, &q.user, client, &changes).await;
+	}
+	for (update, (_, props)) in updates.iter().zip(&domains) {
+		hooks::run_post_update(&state.hooks, &props.hooks.post_update, update);
+	}
+	if let Some(mqtt) = &config.mqtt {
+		mqtt::publish_changes(mqtt, &changes);
+	}
+	if let Some(notify) = &config.notify {
+		notify::send(notify, &updates);
+	}
+	// The update program failed for some of the domains
+	result?;
+
+	if let Some(verify) = verify {
+		if !wait_until_visible(verify, &updates).await {
+			error!(
+				"The updated records did not become visible within {:?}",
+				verify.timeout
+			);
+			return Err(warp::reply::with_status(
+				"Not verified".to_string(),
+				StatusCode::GATEWAY_TIMEOUT,
+			));
+		}
+		info!("Verified that the updated records are visible");
+	}
+
+	info!("Successfully processed update request");
+	Ok(success(&config.responses, q, &updates, &changes))
+}
+
+async fn is_visible(server: SocketAddr, update: &DomainUpdate<'_>) -> Result<bool> {
+	let domain = update.domain;
+	if let Some(ipv4) = update.ipv4 {
+		if !dns::query(server, domain, RecordType::A)
+			.await?
+			.contains(&IpAddr::V4(ipv4))
+		{
+			return Ok(false);
+		}
+	}
+	if let Some(ipv6) = update.ipv6 {
+		if !dns::query(server, domain, RecordType::Aaaa)
+			.await?
+			.contains(&IpAddr::V6(ipv6))
+		{
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+/// Poll the DNS server until all updated records are visible there or the configured timeout is reached
+async fn wait_until_visible(verify: &Verify, updates: &[DomainUpdate<'_>]) -> bool {
+	let check_all = async {
+		for update in updates {
+			loop {
+				let check = is_visible(verify.server, update);
+				match tokio::time::timeout(DNS_QUERY_TIMEOUT, check).await {
+					Ok(Ok(true)) => break,
+					Ok(Ok(false)) => debug!("Update of {} is not visible yet", update.domain),
+					Ok(Err(e)) => warn!(
+						"Error querying {} for {}: {e}",
+						verify.server, update.domain
+					),
+					Err(_) => warn!("DNS query to {} timed out", verify.server),
+				}
+				tokio::time::sleep(VERIFY_INTERVAL).await;
+			}
+		}
 	};
+	tokio::time::timeout(verify.timeout, check_all)
+		.await
+		.is_ok()
+}
 
-	info!("Authentication successful");
+fn check_allowed_parameters(
+	features: Features,
+	user: &User,
+	q: &QueryParameters,
+) -> Result<(), WithStatus<String>> {
+	let lan_prefix = q.ipv6lanprefix.is_some() || q.prefixlen.is_some();
+	let disabled = [
+		("ipv4", q.ipv4.is_some() && !features.ipv4),
+		("ipv6", q.ipv6.is_some() && !features.ipv6),
+		("ipv6lanprefix", lan_prefix && !features.ipv6),
+	];
+	for (parameter, is_disabled) in disabled {
+		if is_disabled {
+			warn!("The `{parameter}` parameter is disabled in the features of the configuration");
+			return Err(warp::reply::with_status(
+				format!("Parameter {parameter} not supported"),
+				StatusCode::BAD_REQUEST,
+			));
+		}
+	}
+	let forbidden = [
+		("ipv4", q.ipv4.is_some() && !user.allow_ipv4),
+		("ipv6", q.ipv6.is_some() && !user.allow_ipv6),
+		("ipv6lanprefix", lan_prefix && !user.allow_ipv6lanprefix),
+	];
+	for (parameter, is_forbidden) in forbidden {
+		if is_forbidden {
+			warn!(
+				"User {} is not allowed to use the `{parameter}` parameter",
+				Username(&q.user)
+			);
+			return Err(warp::reply::with_status(
+				format!("Parameter {parameter} not allowed"),
+				StatusCode::FORBIDDEN,
+			));
+		}
+	}
+	Ok(())
+}
+
+/// Replace the placeholders in an argument or environment variable of the update program.
+/// An address which is not updated is replaced with an empty string.
+#[allow(clippy::literal_string_with_formatting_args)]
+fn fill_placeholders(template: &str, update: &DomainUpdate) -> String {
+	let ipv4 = update.ipv4.map(|ipv4| ipv4.to_string()).unwrap_or_default();
+	let ipv6 = update.ipv6.map(|ipv6| ipv6.to_string()).unwrap_or_default();
+	template
+		.replace("{domain}", update.domain)
+		.replace("{ttl}", &update.ttl.to_string())
+		.replace("{ipv4}", &ipv4)
+		.replace("{ipv6}", &ipv6)
+}
 
-	let command = build_command_string(config, user, q);
+/// Log the domains for which the shadow program had a different result than the update program.
+/// The result of the shadow program is never reported to the client.
+fn compare_shadow(updates: &[DomainUpdate], succeeded: &[bool], shadow_succeeded: &[bool]) {
+	let outcome = |success: bool| if success { "succeeded" } else { "failed" };
+	let mut differences = 0;
+	for ((update, &main), &shadow) in updates.iter().zip(succeeded).zip(shadow_succeeded) {
+		if main != shadow {
+			differences += 1;
+			warn!(
+				"The shadow program {} for {} while the update program {}",
+				outcome(shadow),
+				update.domain,
+				outcome(main)
+			);
+		}
+	}
+	if differences == 0 {
+		debug!("The shadow program had the same results as the update program");
+	}
+}
 
-	let mut child = match Command::new(&config.update_program.bin)
-		.args(&config.update_program.args)
+/// Run the shadow program with the same updates in the background, so it never delays the response or makes the request time out.
+/// Its results are compared to the ones of the update program, which are sent through the returned channel, when both finished.
+/// It is killed if it takes longer than `limits.request_timeout` and skipped while `MAX_SHADOW_RUNS` runs are still going on.
+fn spawn_shadow(
+	config: &Arc<Config>,
+	state: &Arc<State>,
+	username: &str,
+	client: ClientAddr,
+	updates: &[DomainUpdate],
+) -> tokio::sync::oneshot::Sender<Vec<bool>> {
+	let (sender, receiver) = tokio::sync::oneshot::channel::<Vec<bool>>();
+	let config = Arc::clone(config);
+	let shadow_state = Arc::clone(state);
+	let username = username.to_string();
+	let source_ip = client.0.map(|addr| addr.ip().to_canonical());
+	let id = request_id::get();
+	// The domains may come from the query, which does not outlive the request
+	let owned_updates: Vec<_> = updates
+		.iter()
+		.map(|update| {
+			(
+				update.domain.to_string(),
+				update.ttl,
+				update.ipv4,
+				update.ipv6,
+			)
+		})
+		.collect();
+	let run = async move {
+		let updates: Vec<_> = owned_updates
+			.iter()
+			.map(|(domain, ttl, ipv4, ipv6)| DomainUpdate {
+				domain,
+				ttl: *ttl,
+				ipv4: *ipv4,
+				ipv6: *ipv6,
+			})
+			.collect();
+		let Some(shadow_program) = &config.shadow_program else {
+			return;
+		};
+		let run = async {
+			logging::set_domains(updates.iter().map(|update| update.domain));
+			let timeout = config.limits.request_timeout;
+			let shadow_run = Box::pin(run_update_programs(
+				&config,
+				&shadow_state,
+				shadow_program,
+				&updates,
+				true,
+			));
+			// Dropping the run kills the shadow program
+			let Ok((shadow_succeeded, _)) = tokio::time::timeout(timeout, shadow_run).await else {
+				warn!("The shadow program did not finish within {timeout:?} and was killed");
+				return;
+			};
+			if let Ok(succeeded) = receiver.await {
+				compare_shadow(&updates, &succeeded, &shadow_succeeded);
+			} else {
+				warn!("The update program did not finish, the results of the shadow program are not compared");
+			}
+		};
+		let run = logging::scope(&username, source_ip, run);
+		match id {
+			Some(id) => request_id::scope(id, run).await,
+			None => run.await,
+		}
+	};
+	if !state.shadow_runs.try_spawn(run, MAX_SHADOW_RUNS) {
+		warn!("Not running the shadow program, {MAX_SHADOW_RUNS} runs of it are still going on");
+	}
+	sender
+}
+
+/// Run an update program, or the programs of the address families if they have their own.
+/// Returns whether each update succeeded and the first error.
+async fn run_update_programs(
+	config: &Config,
+	state: &State,
+	update_program: &UpdateProgram,
+	updates: &[DomainUpdate<'_>],
+	shadow: bool,
+) -> (Vec<bool>, Result<(), WithStatus<String>>) {
+	let ipv4_program = update_program.for_family(update_program.ipv4.as_ref());
+	let ipv6_program = update_program.for_family(update_program.ipv6.as_ref());
+	if ipv4_program.is_none() && ipv6_program.is_none() {
+		return run_in_mode(config, state, update_program, updates, shadow).await;
+	}
+
+	let mut succeeded = vec![true; updates.len()];
+	let mut result = Ok(());
+	let families = [(ipv4_program, true), (ipv6_program, false)];
+	for (program, is_ipv4) in families {
+		// Only the address of this family, and only for the domains where it changes
+		let (indices, family_updates): (Vec<_>, Vec<_>) = updates
+			.iter()
+			.enumerate()
+			.filter(|(_, update)| {
+				if is_ipv4 {
+					update.ipv4.is_some()
+				} else {
+					update.ipv6.is_some()
+				}
+			})
+			.map(|(i, update)| {
+				let family_update = if is_ipv4 {
+					DomainUpdate {
+						ipv6: None,
+						..*update
+					}
+				} else {
+					DomainUpdate {
+						ipv4: None,
+						..*update
+					}
+				};
+				(i, family_update)
+			})
+			.unzip();
+		if family_updates.is_empty() {
+			continue;
+		}
+		let program = program.as_ref().unwrap_or(update_program);
+		let (family_succeeded, family_result) =
+			run_in_mode(config, state, program, &family_updates, shadow).await;
+		for (i, family_succeeded) in indices.into_iter().zip(family_succeeded) {
+			succeeded[i] &= family_succeeded;
+		}
+		result = result.and(family_result);
+	}
+	(succeeded, result)
+}
+
+/// Count a run of the update program in the metrics, labeled with the file name of the program
+fn record_run(
+	config: &Config,
+	state: &State,
+	update_program: &UpdateProgram,
+	updates: &[DomainUpdate],
+	duration: Duration,
+	success: bool,
+) {
+	let Some(metrics) = &config.metrics else {
+		return;
+	};
+	let bin = Path::new(&update_program.bin);
+	let backend = bin
+		.file_name()
+		.map_or_else(|| bin.to_string_lossy(), |name| name.to_string_lossy());
+	state.metrics.record_run(
+		metrics,
+		&backend,
+		updates.iter().map(|update| update.domain),
+		duration,
+		success,
+	);
+}
+
+/// Run the update program once for all updates or once per update, depending on the mode
+async fn run_in_mode(
+	config: &Config,
+	state: &State,
+	update_program: &UpdateProgram,
+	updates: &[DomainUpdate<'_>],
+	shadow: bool,
+) -> (Vec<bool>, Result<(), WithStatus<String>>) {
+	if update_program.mode() == UpdateMode::Batch {
+		let start = Instant::now();
+		let command = build_command_string(update_program, updates);
+		let result = run_update_program(update_program, None, &command, shadow).await;
+		record_run(
+			config,
+			state,
+			update_program,
+			updates,
+			start.elapsed(),
+			result.is_ok(),
+		);
+		return (vec![result.is_ok(); updates.len()], result);
+	}
+	let semaphore = Semaphore::new(update_program.parallelism());
+	let runs: Vec<_> = updates
+		.iter()
+		.map(|update| {
+			run_update_program_for(config, state, update_program, update, &semaphore, shadow)
+		})
+		.collect();
+	let results = future::join_all(runs).await;
+	let succeeded = results.iter().map(Result::is_ok).collect();
+	let result = results.into_iter().find(Result::is_err).unwrap_or(Ok(()));
+	(succeeded, result)
+}
+
+async fn run_update_program_for(
+	config: &Config,
+	state: &State,
+	update_program: &UpdateProgram,
+	update: &DomainUpdate<'_>,
+	semaphore: &Semaphore,
+	shadow: bool,
+) -> Result<(), WithStatus<String>> {
+	// The semaphore is never closed
+	let _permit = semaphore.acquire().await;
+	let start = Instant::now();
+	let updates = std::slice::from_ref(update);
+	let command = build_command_string(update_program, updates);
+	let result = run_update_program(update_program, Some(update), &command, shadow).await;
+	record_run(
+		config,
+		state,
+		update_program,
+		updates,
+		start.elapsed(),
+		result.is_ok(),
+	);
+	if result.is_err() {
+		error!(
+			"{}The update of {} failed",
+			log_prefix(shadow),
+			update.domain
+		);
+	}
+	result
+}
+
+/// Distinguishes the errors of the shadow program from the ones of the update program
+const fn log_prefix(shadow: bool) -> &'static str {
+	if shadow {
+		"shadow: "
+	} else {
+		""
+	}
+}
+
+async fn run_update_program(
+	update_program: &UpdateProgram,
+	update: Option<&DomainUpdate<'_>>,
+	command: &str,
+	shadow: bool,
+) -> Result<(), WithStatus<String>> {
+	let prefix = log_prefix(shadow);
+	let fill = |template: &String| {
+		update.map_or_else(
+			|| template.clone(),
+			|update| fill_placeholders(template, update),
+		)
+	};
+	let mut command_builder = Command::new(&update_program.bin);
+	command_builder.args(update_program.args.iter().map(fill));
+	if update_program.clear_env {
+		command_builder.env_clear();
+		for name in &update_program.env_allowlist {
+			if let Some(value) = std::env::var_os(name) {
+				command_builder.env(name, value);
+			}
+		}
+	}
+	if let Some(working_directory) = &update_program.working_directory {
+		command_builder.current_dir(working_directory);
+	}
+	if let Some(proxy) = &update_program.proxy {
+		// Most HTTP clients (e.g. curl) only look at one of these
+		for var in [
+			"http_proxy",
+			"https_proxy",
+			"HTTP_PROXY",
+			"HTTPS_PROXY",
+			"ALL_PROXY",
+		] {
+			command_builder.env(var, proxy);
+		}
+	}
+	for (name, value) in &update_program.env {
+		command_builder.env(name, fill(value));
+	}
+
+	let mut child = match command_builder
 		.stdin(Stdio::piped())
 		.stdout(Stdio::piped())
 		.stderr(Stdio::piped())
+		.kill_on_drop(true)
 		.spawn()
 	{
 		Ok(v) => v,
 		Err(e) => {
-			error!("Error spawning child process: {e}");
+			error!(
+				"{prefix}Error spawning child process for request {}: {e}",
+				request_id::current()
+			);
 			return Err(warp::reply::with_status(
-				e.to_string(),
+				"ERROR".to_string(),
 				StatusCode::INTERNAL_SERVER_ERROR,
 			));
 		}
 	};
 
 	if let Some(mut stdin) = child.stdin.take() {
-		if let Err(e) = stdin.write_all(command.as_bytes()) {
-			error!("Error writing command to child process: {e}");
+		if let Err(e) = stdin.write_all(command.as_bytes()).await {
+			error!(
+				"{prefix}Error writing command to child process for request {}: {e}",
+				request_id::current()
+			);
 			return Err(warp::reply::with_status(
-				e.to_string(),
+				"ERROR".to_string(),
 				StatusCode::INTERNAL_SERVER_ERROR,
 			));
-		};
+		}
 	}
 
-	let output = match child.wait_with_output() {
+	let output = match child.wait_with_output().await {
 		Ok(v) => v,
 		Err(e) => {
-			error!("Error waiting for the output of the child process: {e}");
+			error!(
+				"{prefix}Error waiting for the output of the child process for request {}: {e}",
+				request_id::current()
+			);
 			return Err(warp::reply::with_status(
-				e.to_string(),
+				"ERROR".to_string(),
 				StatusCode::INTERNAL_SERVER_ERROR,
 			));
 		}
@@ -144,20 +1621,22 @@ pub fn update(config: &Config, q: &QueryParameters) -> Result<impl Reply, impl R
 
 	let status = output.status;
 	if !status.success() {
-		error!("The update program failed with {status}");
+		error!(
+			"{prefix}The update program failed with {status} for request {}",
+			request_id::current()
+		);
 		let stdout = String::from_utf8_lossy(&output.stdout);
 		if !stdout.is_empty() {
-			error!("and stdout: `{stdout}`");
+			error!("{prefix}and stdout: `{stdout}`");
 		}
 		let stderr = String::from_utf8_lossy(&output.stderr);
 		if !stderr.is_empty() {
-			error!("and stderr: `{stderr}`");
+			error!("{prefix}and stderr: `{stderr}`");
 		}
 		return Err(warp::reply::with_status(
 			"ERROR".to_string(),
 			StatusCode::INTERNAL_SERVER_ERROR,
 		));
 	}
-	info!("Successfully processed update request");
-	Ok(warp::reply::with_status("ok".to_string(), StatusCode::OK))
+	Ok(())
 }
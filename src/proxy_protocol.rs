@@ -0,0 +1,99 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Parser for the header of the PROXY protocol, see https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use color_eyre::eyre::{eyre, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read the PROXY protocol header from the beginning of the stream without consuming any bytes after it.
+/// Returns the source address of the original connection if the proxy sent one.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+	let mut start = [0; 12];
+	stream.read_exact(&mut start).await?;
+	if start == V2_SIGNATURE {
+		read_v2(stream).await
+	} else if start.starts_with(b"PROXY ") {
+		read_v1(stream, &start).await
+	} else {
+		Err(eyre!(
+			"Connection does not start with a PROXY protocol header"
+		))
+	}
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+	let mut line = start.to_vec();
+	// Read byte by byte so nothing after the header is lost
+	while !line.ends_with(b"\r\n") {
+		if line.len() >= V1_MAX_LENGTH {
+			return Err(eyre!("PROXY protocol v1 header is too long"));
+		}
+		line.push(stream.read_u8().await?);
+	}
+	let line = std::str::from_utf8(&line[..line.len() - 2])?;
+	parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+	let fields: Vec<&str> = line.split(' ').collect();
+	match fields.as_slice() {
+		["PROXY", "UNKNOWN", ..] => Ok(None),
+		["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] =>
+		{
+			let ip: IpAddr = source.parse()?;
+			if ip.is_ipv4() != (*protocol == "TCP4") {
+				return Err(eyre!("Address {ip} does not match protocol {protocol}"));
+			}
+			Ok(Some(SocketAddr::new(ip, source_port.parse()?)))
+		}
+		_ => Err(eyre!("Invalid PROXY protocol v1 header `{line}`")),
+	}
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+	let version_command = stream.read_u8().await?;
+	let family = stream.read_u8().await?;
+	let length = stream.read_u16().await?;
+	let mut addresses = vec![0; usize::from(length)];
+	stream.read_exact(&mut addresses).await?;
+	if version_command >> 4 != 2 {
+		return Err(eyre!(
+			"Unsupported PROXY protocol version {}",
+			version_command >> 4
+		));
+	}
+	match version_command & 0x0f {
+		// LOCAL, e.g. health checks of the proxy itself
+		0 => return Ok(None),
+		1 => {}
+		command => return Err(eyre!("Unsupported PROXY protocol command {command}")),
+	}
+	parse_v2_addresses(family >> 4, &addresses)
+}
+
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+	let too_short = || eyre!("PROXY protocol v2 address block is too short");
+	match family {
+		// AF_INET
+		1 => {
+			let block: &[u8; 12] = addresses.get(..12).ok_or_else(too_short)?.try_into()?;
+			let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&block[..4])?);
+			let port = u16::from_be_bytes([block[8], block[9]]);
+			Ok(Some(SocketAddr::new(ip.into(), port)))
+		}
+		// AF_INET6
+		2 => {
+			let block: &[u8; 36] = addresses.get(..36).ok_or_else(too_short)?.try_into()?;
+			let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&block[..16])?);
+			let port = u16::from_be_bytes([block[32], block[33]]);
+			Ok(Some(SocketAddr::new(ip.into(), port)))
+		}
+		// AF_UNSPEC, AF_UNIX
+		_ => Ok(None),
+	}
+}
@@ -0,0 +1,46 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Counts requests per key, e.g. per client address, in fixed time windows
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Number of requests per key in the current fixed time window
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+	windows: Mutex<HashMap<K, (Instant, u32)>>,
+}
+
+impl<K> Default for RateLimiter<K> {
+	fn default() -> Self {
+		Self {
+			windows: Mutex::new(HashMap::new()),
+		}
+	}
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+	/// Count a request and return for how long the client has to wait if it made more than `limit` requests within `window`.
+	/// A limit of zero disables the check.
+	pub fn check(&self, key: K, limit: u32, window: Duration) -> Option<Duration> {
+		if limit == 0 {
+			return None;
+		}
+		let now = Instant::now();
+		let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+		// Forget the keys without a request in the current window, so the map does not grow forever
+		windows.retain(|_, (start, _)| now.duration_since(*start) < window);
+		let (start, count) = windows.entry(key).or_insert((now, 0));
+		let retry_after = if *count >= limit {
+			Some(window.saturating_sub(now.duration_since(*start)))
+		} else {
+			*count += 1;
+			None
+		};
+		drop(windows);
+		retry_after
+	}
+}
@@ -0,0 +1,71 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Sends the last known addresses of a user's domains to the DNS server again, e.g. after restoring it from a backup
+
+use crate::config::Config;
+use crate::process::{self, DomainUpdate};
+use crate::state::State;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+	/// User whose domains are updated again
+	#[arg(long)]
+	user: String,
+}
+
+/// The state after the successful updates in the events file, the later events replacing the earlier ones
+fn load_state(events: &str) -> Result<State> {
+	let state = State::default();
+	for (i, line) in events.lines().enumerate() {
+		let event: Value = serde_json::from_str(line)
+			.wrap_err_with(|| format!("Cannot parse line {} of the events file", i + 1))?;
+		let domains = event["domains"].as_array().map_or(&[][..], Vec::as_slice);
+		for domain in domains {
+			if domain["success"] != true {
+				continue;
+			}
+			let Some(name) = domain["domain"].as_str() else {
+				continue;
+			};
+			state.record(&DomainUpdate {
+				domain: name,
+				ttl: domain["ttl"]
+					.as_u64()
+					.and_then(|ttl| ttl.try_into().ok())
+					.unwrap_or_default(),
+				ipv4: domain["ipv4"].as_str().and_then(|ipv4| ipv4.parse().ok()),
+				ipv6: domain["ipv6"].as_str().and_then(|ipv6| ipv6.parse().ok()),
+			});
+		}
+	}
+	Ok(state)
+}
+
+/// Run the update program with the addresses of the user's domains from the events file
+pub async fn run(config: &Arc<Config>, args: &Args) -> Result<()> {
+	let Some(events_file) = &config.events_file else {
+		return Err(eyre!(
+			"The last known addresses are read from the `events_file`, which is not configured"
+		));
+	};
+	let events = fs::read_to_string(events_file)
+		.wrap_err_with(|| format!("Cannot read events file `{}`", events_file.display()))?;
+	let state = Arc::new(load_state(&events)?);
+	let response = process::refresh(config, &state, &args.user).await;
+	state.hooks.wait().await;
+	state.shadow_runs.wait().await;
+	let status = response.status();
+	let body = warp::hyper::body::to_bytes(response.into_body()).await?;
+	let body = String::from_utf8_lossy(&body);
+	if !status.is_success() {
+		return Err(eyre!("Refresh failed with status {status}: {body}"));
+	}
+	writeln!(std::io::stdout(), "{body}")?;
+	Ok(())
+}
@@ -0,0 +1,52 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Random ID of every update request, so a client can report an error which can be found in the log
+
+use std::fmt;
+use std::future::Future;
+
+tokio::task_local! {
+	static REQUEST_ID: RequestId;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestId(u64);
+
+impl RequestId {
+	#[must_use]
+	pub fn new() -> Self {
+		Self(rand::random())
+	}
+}
+
+impl Default for RequestId {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl fmt::Display for RequestId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:016x}", self.0)
+	}
+}
+
+/// Run `f` with `id` as the ID of the current request
+pub async fn scope<F: Future>(id: RequestId, f: F) -> F::Output {
+	REQUEST_ID.scope(id, f).await
+}
+
+/// The ID of the request which is currently being handled, for log messages
+#[must_use]
+pub fn current() -> String {
+	REQUEST_ID
+		.try_with(ToString::to_string)
+		.unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// The ID of the current request, to keep it in tasks which outlive the request
+#[must_use]
+pub fn get() -> Option<RequestId> {
+	REQUEST_ID.try_with(|id| *id).ok()
+}
@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// A self-signed certificate for trying out a TLS reverse proxy before real certificates exist
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use log::info;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+	/// Host name the certificate is valid for, can be given multiple times
+	#[arg(long, required = true)]
+	hostname: Vec<String>,
+	/// Directory to write `cert.pem` and `key.pem` to
+	#[arg(long, default_value = ".")]
+	out_dir: PathBuf,
+	/// Number of days the certificate is valid for
+	#[arg(long, default_value_t = 365)]
+	days: u32,
+}
+
+/// Create a key and a certificate
+pub fn generate(args: &Args) -> Result<()> {
+	let cert_path = args.out_dir.join("cert.pem");
+	let key_path = args.out_dir.join("key.pem");
+	for path in [&cert_path, &key_path] {
+		if path.exists() {
+			return Err(eyre!("`{}` already exists", path.display()));
+		}
+	}
+	// IP addresses become IP address subject alternative names, everything else DNS names
+	let mut params = CertificateParams::new(args.hostname.clone())
+		.wrap_err("Invalid host name for the certificate")?;
+	params
+		.distinguished_name
+		.push(DnType::CommonName, args.hostname[0].as_str());
+	params.not_before = OffsetDateTime::now_utc();
+	params.not_after = params.not_before + time::Duration::days(args.days.into());
+	let key = KeyPair::generate().wrap_err("Cannot generate a key")?;
+	let cert = params
+		.self_signed(&key)
+		.wrap_err("Cannot create the certificate")?;
+	fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(&key_path)
+		.and_then(|mut file| file.write_all(key.serialize_pem().as_bytes()))
+		.wrap_err_with(|| format!("Cannot write `{}`", key_path.display()))?;
+	fs::write(&cert_path, cert.pem())
+		.wrap_err_with(|| format!("Cannot write `{}`", cert_path.display()))?;
+	info!(
+		"Wrote `{}` and `{}`, valid for {} days",
+		cert_path.display(),
+		key_path.display(),
+		args.days
+	);
+	Ok(())
+}
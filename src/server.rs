@@ -0,0 +1,296 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::{Limits, Listen, ListenAddr, TrustedProxies, UnixSocket};
+use crate::connection::{IdleTimeout, PerIp};
+use crate::forwarded;
+use crate::logging::{self, Redaction};
+use crate::proxy_protocol;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use log::{debug, error, info, warn};
+use nix::sys::stat::{umask, Mode};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use warp::{
+	filters::BoxedFilter,
+	http::StatusCode,
+	hyper::{
+		server::conn::Http,
+		service::{service_fn, Service},
+		Body, Request,
+	},
+	reply::Response,
+	Reply,
+};
+
+pub type Routes = BoxedFilter<(Response,)>;
+
+// Give up on clients which don't send the PROXY protocol header in time
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of the client, inserted into the extensions of every request
+///
+/// This is the address from the forwarding headers of trusted reverse proxies if enabled,
+/// then the address from the PROXY protocol header if enabled, otherwise the peer address of the connection.
+/// It is unknown for Unix sockets without the PROXY protocol.
+/// The port is 0 if the reverse proxy did not send it.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub Option<SocketAddr>);
+
+impl fmt::Display for ClientAddr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (self.0, logging::redaction()) {
+			(Some(addr), Redaction::None) if addr.port() == 0 => write!(f, "{}", addr.ip()),
+			(Some(addr), Redaction::None) => write!(f, "{addr}"),
+			(Some(addr), _) => write!(f, "{}", logging::Ip(addr.ip())),
+			(None, _) => write!(f, "unknown address"),
+		}
+	}
+}
+
+fn bind(addr: SocketAddr, v6only: Option<bool>) -> Result<TcpListener> {
+	let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+	if addr.is_ipv6() {
+		// Without an explicit setting, whether `::` also accepts IPv4 connections depends on the OS
+		if let Some(v6only) = v6only {
+			socket.set_only_v6(v6only)?;
+		}
+	}
+	socket.set_reuse_address(true)?;
+	socket.set_nonblocking(true)?;
+	socket.bind(&addr.into())?;
+	socket.listen(1024)?;
+	Ok(TcpListener::from_std(socket.into())?)
+}
+
+fn bind_unix(socket: &UnixSocket) -> Result<UnixListener> {
+	let path = &socket.path;
+	// A socket file left behind by a previous instance would make bind() fail
+	match fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.file_type().is_socket() => {
+			debug!("Removing stale socket {}", path.display());
+			fs::remove_file(path)?;
+		}
+		Ok(_) => return Err(eyre!("File exists and is not a socket")),
+		Err(e) if e.kind() == ErrorKind::NotFound => {}
+		Err(e) => return Err(e.into()),
+	}
+	// Otherwise anyone could connect between creating the socket and setting its mode and group
+	let old_umask = umask(Mode::from_bits_truncate(0o177));
+	let listener = UnixListener::bind(path);
+	umask(old_umask);
+	let listener = listener?;
+	if let Some(group) = socket.group {
+		std::os::unix::fs::chown(path, None, Some(group.as_raw()))
+			.wrap_err_with(|| format!("Cannot change the group of the socket to {group}"))?;
+	}
+	// Without a configured mode, the socket gets the permissions the umask of dyndnsd allows
+	let mode = socket.mode.unwrap_or_else(|| 0o777 & !old_umask.bits());
+	fs::set_permissions(path, fs::Permissions::from_mode(mode))
+		.wrap_err_with(|| format!("Cannot set the mode of the socket to {mode:o}"))?;
+	Ok(listener)
+}
+
+async fn shutdown_signal() -> Result<()> {
+	let mut sigterm = signal(SignalKind::terminate())?;
+	tokio::select! {
+		result = tokio::signal::ctrl_c() => result?,
+		_ = sigterm.recv() => {}
+	}
+	Ok(())
+}
+
+async fn accept_error(e: &std::io::Error) {
+	error!("Error accepting connection: {e}");
+	// Don't spin if we ran out of file descriptors
+	tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+/// What every connection needs besides the stream
+#[derive(Clone)]
+struct Settings {
+	routes: Routes,
+	proxy_protocol: bool,
+	trusted_proxies: Option<TrustedProxies>,
+	header_read_timeout: Duration,
+	idle_timeout: Duration,
+	per_ip: Arc<PerIp>,
+}
+
+/// The address of the client according to the trusted reverse proxies, or `peer` without them.
+/// Fails if the proxies don't say, falling back to `peer` would make the proxy the client.
+fn request_client(
+	req: &Request<Body>,
+	trusted_proxies: Option<TrustedProxies>,
+	peer: ClientAddr,
+) -> Result<ClientAddr> {
+	let Some(trusted_proxies) = trusted_proxies else {
+		return Ok(peer);
+	};
+	forwarded::client(req.headers(), trusted_proxies).map(|addr| ClientAddr(Some(addr)))
+}
+
+fn serve_connection<S>(mut stream: S, settings: Settings, peer: ClientAddr)
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	tokio::spawn(async move {
+		let client = if settings.proxy_protocol {
+			let header = proxy_protocol::read_header(&mut stream);
+			match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+				Ok(Ok(addr)) => ClientAddr(addr.or(peer.0)),
+				Ok(Err(e)) => {
+					warn!("Error reading PROXY protocol header from {peer}: {e}");
+					return;
+				}
+				Err(_) => {
+					warn!("Timed out reading PROXY protocol header from {peer}");
+					return;
+				}
+			}
+		} else {
+			peer
+		};
+		// Only known after the PROXY protocol header, since the peer may be a proxy.
+		// Behind proxies which only name the client in a forwarding header, the peer is always one of them
+		// and every client would share its limit.
+		let limited = settings.proxy_protocol || settings.trusted_proxies.is_none();
+		let _guard = match client.0 {
+			Some(addr) if limited => {
+				let Some(guard) = settings.per_ip.acquire(addr.ip()) else {
+					warn!("Too many connections from {client}, closing the new one");
+					return;
+				};
+				Some(guard)
+			}
+			_ => None,
+		};
+		let in_progress = Arc::new(AtomicUsize::new(0));
+		let mut service = warp::service(settings.routes);
+		let service = service_fn({
+			let in_progress = Arc::clone(&in_progress);
+			move |mut req: Request<Body>| {
+				let response =
+					request_client(&req, settings.trusted_proxies, client).map(|client| {
+						req.extensions_mut().insert(client);
+						in_progress.fetch_add(1, Ordering::Relaxed);
+						service.call(req)
+					});
+				let in_progress = Arc::clone(&in_progress);
+				async move {
+					match response {
+						Ok(response) => {
+							let response = response.await;
+							in_progress.fetch_sub(1, Ordering::Relaxed);
+							response
+						}
+						Err(e) => {
+							warn!("Rejecting request from {client}: {e}");
+							Ok(warp::reply::with_status(
+								"Invalid forwarding header",
+								StatusCode::BAD_REQUEST,
+							)
+							.into_response())
+						}
+					}
+				}
+			}
+		});
+		let mut http = Http::new();
+		http.http1_header_read_timeout(settings.header_read_timeout);
+		let result = if settings.idle_timeout.is_zero() {
+			http.serve_connection(stream, service).await
+		} else {
+			let stream = IdleTimeout::new(stream, settings.idle_timeout, in_progress);
+			http.serve_connection(stream, service).await
+		};
+		if let Err(e) = result {
+			debug!("Error serving connection from {client}: {e}");
+		}
+	});
+}
+
+async fn accept_loop(listener: TcpListener, settings: Settings) {
+	loop {
+		match listener.accept().await {
+			Ok((stream, peer)) => {
+				serve_connection(stream, settings.clone(), ClientAddr(Some(peer)));
+			}
+			Err(e) => accept_error(&e).await,
+		}
+	}
+}
+
+async fn accept_loop_unix(listener: UnixListener, settings: Settings) {
+	loop {
+		match listener.accept().await {
+			Ok((stream, _)) => {
+				serve_connection(stream, settings.clone(), ClientAddr(None));
+			}
+			Err(e) => accept_error(&e).await,
+		}
+	}
+}
+
+pub async fn run(listen: &Listen, limits: &Limits, routes: Routes) -> Result<()> {
+	let settings = Settings {
+		routes,
+		proxy_protocol: false,
+		trusted_proxies: listen.trusted_proxies,
+		header_read_timeout: limits.header_read_timeout,
+		idle_timeout: limits.idle_timeout,
+		per_ip: Arc::new(PerIp::new(limits.max_connections_per_ip)),
+	};
+	let mut tasks = JoinSet::new();
+	for &ListenAddr {
+		addr,
+		proxy_protocol,
+	} in &listen.addrs
+	{
+		let listener =
+			bind(addr, listen.v6only).wrap_err_with(|| format!("Cannot listen on {addr}"))?;
+		info!("Listening on {addr}");
+		let settings = Settings {
+			proxy_protocol,
+			..settings.clone()
+		};
+		tasks.spawn(accept_loop(listener, settings));
+	}
+	if let Some(socket) = &listen.unix {
+		let path = socket.path.display();
+		let listener =
+			bind_unix(socket).wrap_err_with(|| format!("Cannot listen on Unix socket `{path}`"))?;
+		info!("Listening on Unix socket {path}");
+		let settings = Settings {
+			proxy_protocol: socket.proxy_protocol,
+			..settings
+		};
+		tasks.spawn(accept_loop_unix(listener, settings));
+	}
+
+	let result = tokio::select! {
+		Some(result) = tasks.join_next() => result.wrap_err("Listener task failed"),
+		result = shutdown_signal() => {
+			info!("Shutting down");
+			result
+		}
+	};
+
+	if let Some(socket) = &listen.unix {
+		if let Err(e) = fs::remove_file(&socket.path) {
+			error!("Cannot remove Unix socket `{}`: {e}", socket.path.display());
+		}
+	}
+	result
+}
@@ -0,0 +1,121 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::auth_cache::AuthFailures;
+use crate::background;
+use crate::breaker::Breaker;
+use crate::dedupe::Dedupe;
+use crate::events;
+use crate::metrics::Counters;
+use crate::process::DomainUpdate;
+use crate::rate_limit::RateLimiter;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The addresses of a domain as last sent to the update program
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Addresses {
+	pub ipv4: Option<Ipv4Addr>,
+	pub ipv6: Option<Ipv6Addr>,
+}
+
+/// An attempt to update a domain, successful or not
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryEntry {
+	pub time: SystemTime,
+	pub ipv4: Option<Ipv4Addr>,
+	pub ipv6: Option<Ipv6Addr>,
+	pub success: bool,
+	/// Source address of the request, unknown for the subcommands
+	pub client: Option<IpAddr>,
+}
+
+/// Runtime state shared between all requests.
+/// It only lives in memory, so everything is unknown again after a restart.
+#[derive(Debug, Default)]
+pub struct State {
+	addresses: Mutex<HashMap<String, Addresses>>,
+	history: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+	pub auth_failures: AuthFailures,
+	pub breaker: Breaker,
+	pub checkip: RateLimiter<IpAddr>,
+	pub dedupe: Dedupe,
+	pub events: events::Writer,
+	pub metrics: Counters,
+	/// Post-update hooks which are still running
+	pub hooks: background::Tasks,
+	pub shadow_runs: background::Tasks,
+	/// Update requests per user, for `quota.max_updates_per_day`
+	pub daily_updates: RateLimiter<String>,
+}
+
+impl State {
+	/// The addresses last sent to the update program for `domain`
+	pub fn addresses(&self, domain: &str) -> Addresses {
+		self.addresses
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner)
+			.get(domain)
+			.copied()
+			.unwrap_or_default()
+	}
+
+	/// Remember the addresses of a successful update and return the previous ones
+	pub fn record(&self, update: &DomainUpdate) -> Addresses {
+		let mut addresses = self
+			.addresses
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner);
+		let entry = addresses.entry(update.domain.to_string()).or_default();
+		let old = *entry;
+		if update.ipv4.is_some() {
+			entry.ipv4 = update.ipv4;
+		}
+		if update.ipv6.is_some() {
+			entry.ipv6 = update.ipv6;
+		}
+		drop(addresses);
+		old
+	}
+
+	/// Add an attempt to update a domain to its history, which keeps the last `length` entries
+	pub fn record_history(
+		&self,
+		update: &DomainUpdate,
+		success: bool,
+		client: Option<IpAddr>,
+		length: usize,
+	) {
+		if length == 0 {
+			return;
+		}
+		let mut history = self
+			.history
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner);
+		let entries = history.entry(update.domain.to_string()).or_default();
+		if entries.len() >= length {
+			entries.pop_front();
+		}
+		entries.push_back(HistoryEntry {
+			time: SystemTime::now(),
+			ipv4: update.ipv4,
+			ipv6: update.ipv6,
+			success,
+			client,
+		});
+		drop(history);
+	}
+
+	/// The recent attempts to update `domain`, the oldest first
+	pub fn history(&self, domain: &str) -> Vec<HistoryEntry> {
+		self.history
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner)
+			.get(domain)
+			.map(|entries| entries.iter().copied().collect())
+			.unwrap_or_default()
+	}
+}
@@ -0,0 +1,42 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Counters and timings pushed to a StatsD server, as an alternative to scraping `/metrics`
+
+use crate::config::Statsd;
+use log::warn;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+async fn send_datagram(server: &str, datagram: &[u8]) -> std::io::Result<()> {
+	let addr = tokio::net::lookup_host(server)
+		.await?
+		.next()
+		.ok_or(std::io::ErrorKind::NotFound)?;
+	let local: SocketAddr = if addr.is_ipv4() {
+		([0, 0, 0, 0], 0).into()
+	} else {
+		(Ipv6Addr::UNSPECIFIED, 0).into()
+	};
+	let socket = UdpSocket::bind(local).await?;
+	socket.send_to(datagram, addr).await?;
+	Ok(())
+}
+
+/// Count a finished update request and its duration.
+/// The datagram is sent in the background and lost metrics are only logged.
+pub fn record(statsd: &Statsd, success: bool, duration: Duration) {
+	let prefix = &statsd.prefix;
+	let result = if success { "success" } else { "failure" };
+	let datagram = format!(
+		"{prefix}.updates.{result}:1|c\n{prefix}.request_duration:{}|ms",
+		duration.as_millis()
+	);
+	let server = statsd.server.clone();
+	tokio::spawn(async move {
+		if let Err(e) = send_datagram(&server, datagram.as_bytes()).await {
+			warn!("Cannot send metrics to StatsD server {server}: {e}");
+		}
+	});
+}
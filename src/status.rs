@@ -0,0 +1,62 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// The recent updates of the domains of a user, so they can see when their router last checked in
+
+use crate::config::{Config, Role};
+use crate::logging::Username;
+use crate::process;
+use crate::server::ClientAddr;
+use crate::state::{HistoryEntry, State};
+use log::info;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use warp::{http::StatusCode, reply::Response, Reply};
+
+fn entry_json(entry: &HistoryEntry) -> Value {
+	json!({
+		"time": humantime::format_rfc3339_seconds(entry.time).to_string(),
+		"ipv4": entry.ipv4,
+		"ipv6": entry.ipv6,
+		"success": entry.success,
+		"client": entry.client,
+	})
+}
+
+/// Answer with the history of the domains of the user as JSON.
+/// Admins can ask for any domain with the `domain` parameter.
+pub async fn reply<S: BuildHasher + Sync>(
+	config: &Config,
+	state: &State,
+	client: ClientAddr,
+	query: &HashMap<String, String, S>,
+) -> Response {
+	let username = query.get("user").map_or("", String::as_str);
+	let pass = query.get("pass").map_or("", String::as_str);
+	info!("Status request from user `{}`", Username(username));
+	let user = match process::authenticate(config, state, client, username, pass).await {
+		Ok(user) => user,
+		Err(reply) => return reply.into_response(),
+	};
+	let domains: Vec<&str> = match query.get("domain") {
+		Some(domain) if user.role == Role::Admin => vec![domain],
+		Some(_) => {
+			return warp::reply::with_status("Not authorized", StatusCode::FORBIDDEN)
+				.into_response();
+		}
+		None => {
+			let mut domains: Vec<_> = user.domains.keys().map(String::as_str).collect();
+			domains.sort_unstable();
+			domains
+		}
+	};
+	let history: Map<String, Value> = domains
+		.into_iter()
+		.map(|domain| {
+			let entries = state.history(domain).iter().map(entry_json).collect();
+			(domain.to_string(), Value::Array(entries))
+		})
+		.collect();
+	warp::reply::json(&json!({ "domains": history })).into_response()
+}
@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// A small HTML page showing the domains of a user and a form for manual updates
+
+use crate::config::Config;
+use crate::logging::Username;
+use crate::process;
+use crate::server::ClientAddr;
+use crate::state::State;
+use base64ct::{Base64, Encoding};
+use log::{info, warn};
+use std::fmt::{Display, Write as _};
+use warp::{
+	http::StatusCode,
+	reply::{Html, Response},
+	Reply,
+};
+
+/// Escape text for use in HTML content and attribute values
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+fn or_unknown<T: Display>(address: Option<T>) -> String {
+	address.map_or_else(|| "unknown".to_string(), |address| address.to_string())
+}
+
+/// Extract the user name and password from the value of a basic `Authorization` header
+fn parse_basic_auth(authorization: &str) -> Option<(String, String)> {
+	let encoded = authorization.strip_prefix("Basic ")?;
+	let decoded = Base64::decode_vec(encoded.trim()).ok()?;
+	let decoded = String::from_utf8(decoded).ok()?;
+	let (user, pass) = decoded.split_once(':')?;
+	Some((user.to_string(), pass.to_string()))
+}
+
+/// Ask the browser for the credentials
+fn unauthorized() -> Response {
+	warp::reply::with_header(
+		warp::reply::with_status("Not authorized", StatusCode::UNAUTHORIZED),
+		"WWW-Authenticate",
+		"Basic realm=\"dyndnsd\", charset=\"UTF-8\"",
+	)
+	.into_response()
+}
+
+fn render(config: &Config, state: &State, username: &str) -> Html<String> {
+	let user = &config.users[username];
+	let mut domains: Vec<_> = user.domains.keys().collect();
+	domains.sort();
+	let mut rows = String::new();
+	for domain in domains {
+		let addresses = state.addresses(domain);
+		let _ = write!(
+			rows,
+			"<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+			escape(domain),
+			or_unknown(addresses.ipv4),
+			or_unknown(addresses.ipv6),
+		);
+	}
+	let username = escape(username);
+	warp::reply::html(format!(
+		r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>dyndnsd</title>
+</head>
+<body>
+<h1>Domains of {username}</h1>
+<p>The addresses are the ones last sent to the DNS server since dyndnsd was started.</p>
+<table>
+<tr><th>Domain</th><th>IPv4</th><th>IPv6</th></tr>
+{rows}
+</table>
+<h2>Update</h2>
+<p>Leave an address empty to keep it, or enter <code>auto</code> to use the address this page was loaded from.</p>
+<form action="update" method="post" onsubmit="for (const input of this.elements) input.disabled = !input.value">
+<input type="hidden" name="user" value="{username}">
+<p><label>Password <input type="password" name="pass" required></label></p>
+<p><label>IPv4 <input name="ipv4"></label></p>
+<p><label>IPv6 <input name="ipv6"></label></p>
+<p><button>Update</button></p>
+</form>
+</body>
+</html>
+"#
+	))
+}
+
+/// Serve the page if the request carries valid credentials
+pub async fn page(
+	config: &Config,
+	state: &State,
+	client: ClientAddr,
+	authorization: Option<String>,
+) -> Response {
+	let Some((username, pass)) = authorization.as_deref().and_then(parse_basic_auth) else {
+		return unauthorized();
+	};
+	info!("Web UI request from user `{}`", Username(&username));
+	match process::authenticate(config, state, client, &username, &pass).await {
+		Ok(_) => render(config, state, &username).into_response(),
+		Err(reply) => {
+			let response = reply.into_response();
+			if response.status() == StatusCode::FORBIDDEN {
+				warn!("Rejected credentials for the web UI");
+				unauthorized()
+			} else {
+				response
+			}
+		}
+	}
+}
@@ -0,0 +1,32 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Stand-in for the update program in the integration tests.
+// Usage: mock-update-program <record file> [exit status] [seconds to sleep]
+// Appends everything it reads from stdin and then a line with its exit status to the record file.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::process::ExitCode;
+use std::time::Duration;
+
+fn main() -> std::io::Result<ExitCode> {
+	let mut args = std::env::args().skip(1);
+	let record = args.next().expect("Missing path of the record file");
+	let status: u8 = args.next().map_or(0, |status| {
+		status.parse().expect("Cannot parse the exit status")
+	});
+	let sleep: u64 = args.next().map_or(0, |sleep| {
+		sleep
+			.parse()
+			.expect("Cannot parse the number of seconds to sleep")
+	});
+
+	let mut stdin = String::new();
+	std::io::stdin().read_to_string(&mut stdin)?;
+	std::thread::sleep(Duration::from_secs(sleep));
+
+	let mut file = OpenOptions::new().create(true).append(true).open(record)?;
+	writeln!(file, "{stdin}exit {status}")?;
+	Ok(ExitCode::from(status))
+}
@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Runs dyndnsd with the mock update program on an ephemeral port
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Password 123456, with cheap parameters to keep the tests fast
+pub const HASH: &str =
+	"$argon2id$v=19$m=8,t=1,p=1$ynJD38hi9CP6ZEXiB8LW7Q$wffnj+2zrb4G46uMynC44nXuewIDCxCWUBzZiwPooCI";
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Server {
+	child: Child,
+	port: u16,
+	dir: PathBuf,
+}
+
+/// What the update program should do when it is run
+#[derive(Default)]
+pub struct Program {
+	pub exit_status: u8,
+	pub sleep: u64,
+}
+
+impl Server {
+	/// Start dyndnsd with user alice owning example.org, `config` is appended to the generated config file
+	pub fn start(config: &str) -> Self {
+		Self::start_with_program(config, &Program::default())
+	}
+
+	pub fn start_with_program(config: &str, program: &Program) -> Self {
+		Self::spawn(config, "", program)
+	}
+
+	/// Start dyndnsd with `listen` appended to the `[listen]` section
+	pub fn start_with_listen(config: &str, listen: &str) -> Self {
+		Self::spawn(config, listen, &Program::default())
+	}
+
+	fn spawn(config: &str, listen: &str, program: &Program) -> Self {
+		let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+		let dir = std::env::temp_dir().join(format!("dyndnsd-test-{}-{id}", std::process::id()));
+		fs::create_dir_all(&dir).expect("Cannot create temporary directory");
+
+		// There is a small race between finding a free port and dyndnsd binding to it, which is fine for tests
+		let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+			.and_then(|listener| listener.local_addr())
+			.expect("Cannot find a free port")
+			.port();
+
+		let record = dir.join("record");
+		let config = format!(
+			r#"
+{config}
+
+[listen]
+ip = "127.0.0.1"
+port = {port}
+{listen}
+
+[update_program]
+bin = "{bin}"
+args = ["{record}", "{exit_status}", "{sleep}"]
+initial_stdin = "begin\n"
+stdin_per_zone_update = "send\n"
+final_stdin = "quit\n"
+
+[update_program.ipv4]
+stdin = "A {{domain}} {{ttl}} {{ipv4}}\n"
+
+[update_program.ipv6]
+stdin = "AAAA {{domain}} {{ttl}} {{ipv6}}\n"
+
+[users.alice]
+hash = "{HASH}"
+
+[users.alice.domains."example.org"]
+ttl = 60
+ipv6prefixlen = 48
+ipv6suffix = "0:0:0:1::5"
+"#,
+			bin = env!("CARGO_BIN_EXE_mock-update-program"),
+			record = record.display(),
+			exit_status = program.exit_status,
+			sleep = program.sleep,
+		);
+		let config_path = dir.join("config.toml");
+		fs::write(&config_path, config).expect("Cannot write config file");
+
+		let child = Command::new(env!("CARGO_BIN_EXE_dyndnsd"))
+			.arg("--config")
+			.arg(&config_path)
+			.env("RUST_LOG", "debug")
+			.stdout(Stdio::null())
+			.stderr(fs::File::create(dir.join("log")).expect("Cannot create log file"))
+			.spawn()
+			.expect("Cannot start dyndnsd");
+		let mut server = Self { child, port, dir };
+		server.wait_until_listening();
+		server
+	}
+
+	fn wait_until_listening(&mut self) {
+		let start = Instant::now();
+		while TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).is_err() {
+			if let Ok(Some(status)) = self.child.try_wait() {
+				panic!("dyndnsd exited with {status}, log:\n{}", self.log());
+			}
+			assert!(
+				start.elapsed() < STARTUP_TIMEOUT,
+				"dyndnsd did not start listening, log:\n{}",
+				self.log()
+			);
+			std::thread::sleep(Duration::from_millis(20));
+		}
+	}
+
+	/// Send a GET request and return the status code and body of the response
+	pub fn get(&self, path_and_query: &str) -> (u16, String) {
+		let (status, _, body) = self.request("GET", path_and_query, &[]);
+		(status, body)
+	}
+
+	/// Send a request and return the status code, the headers and the body of the response
+	pub fn request(
+		&self,
+		method: &str,
+		path_and_query: &str,
+		headers: &[(&str, &str)],
+	) -> (u16, String, String) {
+		let mut stream = self.connect();
+		let mut request = format!("{method} {path_and_query} HTTP/1.1\r\nHost: localhost\r\n");
+		for (name, value) in headers {
+			request = request + name + ": " + value + "\r\n";
+		}
+		request.push_str("Connection: close\r\n\r\n");
+		stream
+			.write_all(request.as_bytes())
+			.expect("Cannot send request");
+		let mut response = String::new();
+		stream
+			.read_to_string(&mut response)
+			.expect("Cannot read response");
+		let (head, body) = response
+			.split_once("\r\n\r\n")
+			.expect("Response has no header");
+		let status = head
+			.split(' ')
+			.nth(1)
+			.and_then(|status| status.parse().ok())
+			.expect("Response has no status code");
+		(status, head.to_string(), body.to_string())
+	}
+
+	/// Everything the update program got on stdin so far, followed by its exit status for every run
+	pub fn record(&self) -> String {
+		fs::read_to_string(self.dir.join("record")).unwrap_or_default()
+	}
+
+	pub fn connect(&self) -> TcpStream {
+		TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).expect("Cannot connect to dyndnsd")
+	}
+
+	pub fn pid(&self) -> u32 {
+		self.child.id()
+	}
+
+	pub fn log(&self) -> String {
+		fs::read_to_string(self.dir.join("log")).unwrap_or_default()
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+		let _ = fs::remove_dir_all(&self.dir);
+	}
+}
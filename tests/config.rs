@@ -0,0 +1,477 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const BASE: &str = r#"
+[listen]
+ip = "127.0.0.1"
+port = 9841
+
+[limits]
+request_timeout = 0
+
+[update_program]
+bin = "true"
+args = []
+stdin_per_zone_update = "send\n"
+final_stdin = "quit\n"
+
+[update_program.ipv4]
+stdin = "A {domain} {ttl} {ipv4}\n"
+
+[update_program.ipv6]
+stdin = "AAAA {domain} {ttl} {ipv6}\n"
+"#;
+
+const OVERRIDE: &str = r#"
+[limits]
+request_timeout = 5
+
+[users.alice]
+hash = "$argon2id$v=19$m=8,t=1,p=1$ynJD38hi9CP6ZEXiB8LW7Q$wffnj+2zrb4G46uMynC44nXuewIDCxCWUBzZiwPooCI"
+domains = {}
+"#;
+
+fn temp_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("dyndnsd-test-{}-{name}", std::process::id()));
+	fs::create_dir_all(&dir).expect("Cannot create temporary directory");
+	dir
+}
+
+/// Run dyndnsd with the config files and `args`, with a password on stdin
+fn run(configs: &[&Path], args: &[&str]) -> Output {
+	let mut command = Command::new(env!("CARGO_BIN_EXE_dyndnsd"));
+	for config in configs {
+		command.arg("--config").arg(config);
+	}
+	let mut child = command
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.expect("Cannot start dyndnsd");
+	child
+		.stdin
+		.take()
+		.expect("No stdin")
+		.write_all(b"123456\n")
+		.expect("Cannot write password");
+	child.wait_with_output().expect("Cannot wait for dyndnsd")
+}
+
+/// Load the config files by hashing a password with them
+fn load(configs: &[&Path]) -> Output {
+	run(configs, &["hash-password"])
+}
+
+#[test]
+fn later_files_override_earlier_ones() {
+	let dir = temp_dir("merge");
+	let base = dir.join("base.toml");
+	let extra = dir.join("override.toml");
+	fs::write(&base, BASE).expect("Cannot write config file");
+	fs::write(&extra, OVERRIDE).expect("Cannot write config file");
+
+	let output = load(&[&base, &extra]);
+	assert!(output.status.success(), "{output:?}");
+	let output = load(&[&extra, &base]);
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(
+		stderr.contains("request timeout must not be zero"),
+		"{stderr}"
+	);
+
+	let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn directory() {
+	let dir = temp_dir("directory");
+	fs::write(dir.join("10-base.toml"), BASE).expect("Cannot write config file");
+	fs::write(dir.join("20-override.toml"), OVERRIDE).expect("Cannot write config file");
+	fs::write(dir.join("README"), "not a config file").expect("Cannot write file");
+
+	let output = load(&[&dir]);
+	assert!(output.status.success(), "{output:?}");
+
+	let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn strict() {
+	let dir = temp_dir("strict");
+	let base = dir.join("base.toml");
+	let extra = dir.join("override.toml");
+	fs::write(&base, BASE).expect("Cannot write config file");
+	fs::write(
+		&extra,
+		format!("{OVERRIDE}\n[password_policy]\nmin_memory = 19456\n"),
+	)
+	.expect("Cannot write config file");
+
+	let output = run(&[&base, &extra], &["check"]);
+	assert!(output.status.success(), "{output:?}");
+	let output = run(&[&base, &extra], &["check", "--strict"]);
+	assert!(!output.status.success(), "{output:?}");
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(
+		stderr.contains("Weak password hash of user alice"),
+		"{stderr}"
+	);
+
+	let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn unknown_key() {
+	let dir = temp_dir("unknown-key");
+	let base = dir.join("base.toml");
+	let extra = dir.join("override.toml");
+	fs::write(&base, BASE).expect("Cannot write config file");
+	fs::write(&extra, format!("{OVERRIDE}\n[listen]\nprot = 9841\n"))
+		.expect("Cannot write config file");
+
+	let output = run(&[&base, &extra], &["check"]);
+	assert!(output.status.success(), "{output:?}");
+	let output = run(&[&base, &extra], &["check", "--strict"]);
+	assert!(!output.status.success(), "{output:?}");
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(
+		stderr.contains("Unknown key `listen.prot` is ignored"),
+		"{stderr}"
+	);
+
+	let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn template_of_disabled_address_family_is_optional() {
+	let dir = temp_dir("features");
+	let base = dir.join("base.toml");
+	let extra = dir.join("override.toml");
+	let without_ipv6 = BASE.replace("[update_program.ipv6]", "[unused]");
+	fs::write(&base, without_ipv6).expect("Cannot write config file");
+	fs::write(&extra, OVERRIDE).expect("Cannot write config file");
+
+	let output = run(&[&base, &extra], &["check"]);
+	assert!(!output.status.success(), "{output:?}");
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(
+		stderr.contains("The [update_program.ipv6] section is missing"),
+		"{stderr}"
+	);
+
+	fs::write(&extra, format!("{OVERRIDE}\n[features]\nipv6 = false\n"))
+		.expect("Cannot write config file");
+	let output = run(&[&base, &extra], &["check"]);
+	assert!(output.status.success(), "{output:?}");
+
+	let _ = fs::remove_dir_all(&dir);
+}
+
+/// A domain of alice for the tests which run the update program
+const EXAMPLE_ORG: &str = r#"
+[users.alice.domains."example.org"]
+ttl = 60
+ipv6prefixlen = 48
+ipv6suffix = "::1"
+"#;
+
+/// `BASE`, `OVERRIDE` and a config file of the test in a temporary directory, which is removed when dropped.
+/// The config file of the test usually overrides the `[update_program]` and is followed by `EXAMPLE_ORG`.
+/// `{dir}` in it is replaced with the path of the directory.
+struct TestConfig {
+	dir: PathBuf,
+	files: [PathBuf; 3],
+}
+
+impl TestConfig {
+	fn new(name: &str, config: &str) -> Self {
+		let dir = temp_dir(name);
+		let files = ["base.toml", "override.toml", "test.toml"].map(|file| dir.join(file));
+		fs::write(&files[0], BASE).expect("Cannot write config file");
+		fs::write(&files[1], OVERRIDE).expect("Cannot write config file");
+		let test_config = Self { dir, files };
+		test_config.write(config);
+		test_config
+	}
+
+	/// Replace the config file of the test
+	fn write(&self, config: &str) {
+		let config = config.replace("{dir}", &self.dir.display().to_string());
+		fs::write(&self.files[2], format!("{config}{EXAMPLE_ORG}"))
+			.expect("Cannot write config file");
+	}
+
+	fn run(&self, args: &[&str]) -> Output {
+		let files: Vec<_> = self.files.iter().map(PathBuf::as_path).collect();
+		run(&files, args)
+	}
+
+	/// Update the domains of alice with the `oneshot` subcommand
+	fn oneshot(&self, args: &[&str]) -> Output {
+		self.run(&[&["oneshot", "--user", "alice"], args].concat())
+	}
+
+	/// The contents of a file in the directory, e.g. one written by the update program
+	fn read(&self, name: &str) -> String {
+		fs::read_to_string(self.dir.join(name)).expect("File was not written")
+	}
+}
+
+impl Drop for TestConfig {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.dir);
+	}
+}
+
+#[test]
+fn oneshot() {
+	let config = TestConfig::new(
+		"oneshot",
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "cat > {dir}/record"]
+"#,
+	);
+
+	let output = config.oneshot(&["--ipv4", "1.2.3.4"]);
+	assert!(output.status.success(), "{output:?}");
+	assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+	assert_eq!(
+		config.read("record"),
+		"A example.org 60 1.2.3.4\nsend\nquit\n"
+	);
+
+	let output = config.run(&["oneshot", "--user", "mallory", "--ipv4", "1.2.3.4"]);
+	assert!(!output.status.success(), "{output:?}");
+
+	config.write(
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "cat > {dir}/record"]
+
+[users.alice.domains."lan.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 5
+"#,
+	);
+	let output = config.oneshot(&[
+		"--ipv6",
+		"2001:db8:1:2::1",
+		"--prefixlen",
+		"56",
+		"--hostname",
+		"lan.example.org",
+	]);
+	assert!(output.status.success(), "{output:?}");
+	assert_eq!(
+		config.read("record"),
+		"AAAA lan.example.org 60 2001:db8:1::5\nsend\nquit\n"
+	);
+}
+
+#[test]
+fn oneshot_waits_for_hooks() {
+	let config = TestConfig::new(
+		"oneshot-hooks",
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "cat > /dev/null"]
+
+[users.alice.domains."example.org".hooks]
+post_update = ["sleep 1 && echo $DYNDNSD_IPV4 > {dir}/hook"]
+"#,
+	);
+
+	let output = config.oneshot(&["--ipv4", "1.2.3.4"]);
+	assert!(output.status.success(), "{output:?}");
+	assert_eq!(config.read("hook"), "1.2.3.4\n");
+}
+
+#[test]
+fn refresh() {
+	let config = TestConfig::new(
+		"refresh",
+		r#"
+events_file = "{dir}/events"
+
+[update_program]
+bin = "sh"
+args = ["-c", "cat > {dir}/record"]
+
+[users.alice.domains."example.com"]
+ttl = 60
+ipv6prefixlen = 48
+ipv6suffix = "::1"
+"#,
+	);
+
+	let output = config.run(&["refresh", "--user", "alice"]);
+	assert!(!output.status.success(), "{output:?}");
+
+	fs::write(
+		config.dir.join("events"),
+		r#"{"user":"alice","status":200,"domains":[{"domain":"example.org","ttl":60,"ipv4":"1.2.3.4","ipv6":"2001:db8::1","success":true}]}
+{"user":"alice","status":200,"domains":[{"domain":"example.org","ttl":60,"ipv4":"1.2.3.5","ipv6":null,"success":true}]}
+{"user":"alice","status":500,"domains":[{"domain":"example.org","ttl":60,"ipv4":"1.2.3.6","ipv6":null,"success":false}]}
+"#,
+	)
+	.expect("Cannot write events file");
+	let output = config.run(&["refresh", "--user", "alice"]);
+	assert!(output.status.success(), "{output:?}");
+	// example.com has no known addresses
+	assert_eq!(
+		config.read("record"),
+		"A example.org 60 1.2.3.5\nAAAA example.org 60 2001:db8::1\nsend\nquit\n"
+	);
+}
+
+#[test]
+fn placeholders_in_arguments_and_environment() {
+	let config = TestConfig::new(
+		"placeholders",
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "echo \"$0 $1 $TTL $(cat)\" >> {dir}/record", "{domain}", "{ipv4}"]
+env = { TTL = "{ttl}" }
+stdin_per_zone_update = ""
+final_stdin = ""
+
+[update_program.ipv4]
+stdin = "{ipv4}"
+
+[users.alice.domains."example.com"]
+ttl = 300
+ipv6prefixlen = 48
+ipv6suffix = "::1"
+"#,
+	);
+
+	let output = config.oneshot(&["--ipv4", "1.2.3.4"]);
+	assert!(output.status.success(), "{output:?}");
+	let record = config.read("record");
+	let mut runs: Vec<_> = record.lines().collect();
+	runs.sort_unstable();
+	assert_eq!(
+		runs,
+		[
+			"example.com 1.2.3.4 300 1.2.3.4",
+			"example.org 1.2.3.4 60 1.2.3.4"
+		]
+	);
+}
+
+#[test]
+fn per_domain_mode() {
+	// Fails for example.com only
+	let config = TestConfig::new(
+		"per-domain",
+		r#"
+audit_log = "{dir}/audit"
+
+[update_program]
+bin = "sh"
+args = ["-c", "read domain; echo $domain >> {dir}/record; test $domain != example.com"]
+mode = "per-domain"
+parallelism = 2
+stdin_per_zone_update = ""
+final_stdin = ""
+
+[update_program.ipv4]
+stdin = "{domain}\n"
+
+[users.alice.domains."example.com"]
+ttl = 60
+ipv6prefixlen = 48
+ipv6suffix = "::1"
+"#,
+	);
+
+	let output = config.oneshot(&["--ipv4", "1.2.3.4"]);
+	assert!(!output.status.success(), "{output:?}");
+	let record = config.read("record");
+	let mut runs: Vec<_> = record.lines().collect();
+	runs.sort_unstable();
+	assert_eq!(runs, ["example.com", "example.org"]);
+	let audit = config.read("audit");
+	assert!(audit.contains("domain=example.org"), "{audit}");
+	assert!(!audit.contains("domain=example.com"), "{audit}");
+
+	config.write(
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "cat", "{domain}"]
+mode = "batch"
+"#,
+	);
+	let output = config.run(&["check"]);
+	assert!(!output.status.success(), "{output:?}");
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("cannot be used in batch mode"), "{stderr}");
+}
+
+#[test]
+fn update_program_per_address_family() {
+	let config = TestConfig::new(
+		"families",
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "cat >> {dir}/ipv6"]
+
+[update_program.ipv4]
+args = ["-c", "cat >> {dir}/ipv4"]
+"#,
+	);
+
+	let output = config.oneshot(&["--ipv4", "1.2.3.4", "--ipv6", "2001:db8:1:2::5"]);
+	assert!(output.status.success(), "{output:?}");
+	assert_eq!(
+		config.read("ipv4"),
+		"A example.org 60 1.2.3.4\nsend\nquit\n"
+	);
+	assert_eq!(
+		config.read("ipv6"),
+		"AAAA example.org 60 2001:db8:1::1\nsend\nquit\n"
+	);
+}
+
+#[test]
+fn update_program_environment() {
+	let config = TestConfig::new(
+		"environment",
+		r#"
+[update_program]
+bin = "sh"
+args = ["-c", "env > record; pwd >> record"]
+clear_env = true
+env_allowlist = ["HOME"]
+env = { EXTRA = "yes" }
+working_directory = "{dir}"
+"#,
+	);
+
+	let output = config.oneshot(&["--ipv4", "1.2.3.4"]);
+	assert!(output.status.success(), "{output:?}");
+	let record = config.read("record");
+	assert!(record.contains("EXTRA=yes\n"), "{record}");
+	assert!(record.contains("HOME="), "{record}");
+	// Cargo sets these for the tests and dyndnsd inherits them
+	assert!(!record.contains("CARGO"), "{record}");
+	assert!(
+		record.ends_with(&format!("{}\n", config.dir.display())),
+		"{record}"
+	);
+}
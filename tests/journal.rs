@@ -0,0 +1,98 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use dyndnsd::journal::Journal;
+use log::{Level, LevelFilter, Log, Record};
+use nix::cmsg_space;
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use std::fs;
+use std::io::IoSliceMut;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+/// A socket in a temporary directory standing in for the journal, and a `Journal` connected to it
+fn journal(name: &str) -> (PathBuf, UnixDatagram, Journal) {
+	let dir = std::env::temp_dir().join(format!("dyndnsd-test-{}-{name}", std::process::id()));
+	fs::create_dir_all(&dir).expect("Cannot create temporary directory");
+	let path = dir.join("socket");
+	let _ = fs::remove_file(&path);
+	let socket = UnixDatagram::bind(&path).expect("Cannot bind socket");
+	let filter = env_logger::Builder::new()
+		.filter_level(LevelFilter::Info)
+		.build();
+	let journal = Journal::connect(filter, &path).expect("Cannot connect to socket");
+	(dir, socket, journal)
+}
+
+fn log(journal: &Journal, message: &str) {
+	journal.log(
+		&Record::builder()
+			.args(format_args!("{message}"))
+			.level(Level::Warn)
+			.target("dyndnsd")
+			.build(),
+	);
+}
+
+#[test]
+fn fields() {
+	let (dir, socket, journal) = journal("journal");
+	log(&journal, "first line\nsecond line");
+	let mut buffer = vec![0; 4096];
+	let length = socket.recv(&mut buffer).expect("Nothing was sent");
+	let entry = &buffer[..length];
+
+	let message = b"first line\nsecond line";
+	let binary_field = [
+		b"MESSAGE\n".as_slice(),
+		&(message.len() as u64).to_le_bytes(),
+		message,
+		b"\n",
+	]
+	.concat();
+	assert!(
+		entry.windows(binary_field.len()).any(|w| w == binary_field),
+		"{}",
+		String::from_utf8_lossy(entry)
+	);
+	let entry = String::from_utf8_lossy(entry);
+	assert!(entry.contains("\nPRIORITY=4\n"), "{entry}");
+	assert!(entry.contains("\nSYSLOG_IDENTIFIER=dyndnsd\n"), "{entry}");
+
+	let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn oversized_entry() {
+	let (dir, socket, journal) = journal("journal-memfd");
+	let message = "x".repeat(1 << 20);
+	log(&journal, &message);
+
+	let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+	let mut buffer = [0; 16];
+	let mut iov = [IoSliceMut::new(&mut buffer)];
+	let received = recvmsg::<()>(
+		socket.as_raw_fd(),
+		&mut iov,
+		Some(&mut cmsg_buffer),
+		MsgFlags::empty(),
+	)
+	.expect("Nothing was sent");
+	assert_eq!(received.bytes, 0);
+	let fds: Vec<_> = received
+		.cmsgs()
+		.expect("Invalid control messages")
+		.filter_map(|cmsg| match cmsg {
+			ControlMessageOwned::ScmRights(fds) => Some(fds),
+			_ => None,
+		})
+		.flatten()
+		.collect();
+	assert_eq!(fds.len(), 1);
+	let entry = fs::read_to_string(format!("/proc/self/fd/{}", fds[0])).expect("Cannot read memfd");
+	assert!(entry.starts_with(&format!("MESSAGE={message}\n")));
+	assert!(entry.contains("\nPRIORITY=4\n"));
+
+	let _ = fs::remove_dir_all(&dir);
+}
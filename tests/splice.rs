@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+// Property tests for splicing IPv6 addresses
+
+use dyndnsd::process::splice_ipv6_addrs;
+use proptest::prelude::*;
+use std::net::Ipv6Addr;
+
+fn addr() -> impl Strategy<Value = Ipv6Addr> {
+	any::<u128>().prop_map(Ipv6Addr::from)
+}
+
+fn host_mask(prefixlen: u8) -> u128 {
+	if prefixlen == 0 {
+		u128::MAX
+	} else {
+		(1 << (128 - u32::from(prefixlen))) - 1
+	}
+}
+
+proptest! {
+	#[test]
+	fn prefix_bits_come_from_prefix(prefixlen in 0..=128u8, prefix in addr(), suffix in addr()) {
+		let spliced = u128::from(splice_ipv6_addrs(prefixlen, prefix, suffix));
+		let mask = !host_mask(prefixlen);
+		prop_assert_eq!(spliced & mask, u128::from(prefix) & mask);
+	}
+
+	#[test]
+	fn host_bits_come_from_suffix(prefixlen in 0..=128u8, prefix in addr(), suffix in addr()) {
+		let spliced = u128::from(splice_ipv6_addrs(prefixlen, prefix, suffix));
+		let mask = host_mask(prefixlen);
+		prop_assert_eq!(spliced & mask, u128::from(suffix) & mask);
+	}
+
+	#[test]
+	fn idempotent(prefixlen in 0..=128u8, prefix in addr(), suffix in addr()) {
+		let spliced = splice_ipv6_addrs(prefixlen, prefix, suffix);
+		prop_assert_eq!(splice_ipv6_addrs(prefixlen, spliced, suffix), spliced);
+		prop_assert_eq!(splice_ipv6_addrs(prefixlen, prefix, spliced), spliced);
+	}
+
+	#[test]
+	fn prefix_length_0_is_the_suffix(prefix in addr(), suffix in addr()) {
+		prop_assert_eq!(splice_ipv6_addrs(0, prefix, suffix), suffix);
+	}
+
+	#[test]
+	fn prefix_length_128_is_the_prefix(prefix in addr(), suffix in addr()) {
+		prop_assert_eq!(splice_ipv6_addrs(128, prefix, suffix), prefix);
+	}
+}
+
+#[test]
+fn example() {
+	let prefix = "2001:db8:1:2::1".parse().expect("Valid address");
+	let suffix = "0:0:0:1::5".parse().expect("Valid address");
+	assert_eq!(
+		splice_ipv6_addrs(48, prefix, suffix),
+		"2001:db8:1:1::5"
+			.parse::<Ipv6Addr>()
+			.expect("Valid address")
+	);
+}
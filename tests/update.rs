@@ -0,0 +1,1328 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <dyndnsd@luflosi.de>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+mod common;
+
+use common::{Program, Server, HASH};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+const AUTH: &str = "user=alice&pass=123456";
+
+#[test]
+fn updates_both_addresses() {
+	let server = Server::start("");
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4&ipv6=2001:db8:1:2::1"));
+	assert_eq!((status, body.as_str()), (200, "ok"));
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nAAAA example.org 60 2001:db8:1:1::5\nsend\nquit\nexit 0\n"
+	);
+}
+
+#[test]
+fn parameter_combinations() {
+	let server = Server::start("");
+	let mut expected = String::new();
+	for ipv4 in [None, Some("1.2.3.4")] {
+		for ipv6 in [None, Some("2001:db8:1:2::1")] {
+			let mut path = format!("/update?{AUTH}");
+			expected.push_str("begin\n");
+			if let Some(ipv4) = ipv4 {
+				let _ = write!(path, "&ipv4={ipv4}");
+				let _ = writeln!(expected, "A example.org 60 {ipv4}");
+			}
+			if let Some(ipv6) = ipv6 {
+				let _ = write!(path, "&ipv6={ipv6}");
+				expected.push_str("AAAA example.org 60 2001:db8:1:1::5\n");
+			}
+			expected.push_str("send\nquit\nexit 0\n");
+			let (status, body) = server.get(&path);
+			assert_eq!((status, body.as_str()), (200, "ok"), "{path}");
+		}
+	}
+	assert_eq!(server.record(), expected);
+}
+
+#[test]
+fn addresses_are_normalized() {
+	let server = Server::start("");
+	let (status, _) = server.get(&format!(
+		"/update?{AUTH}&ipv4=::ffff:1.2.3.4&ipv6=2001:DB8:1:2:0:0:0:1"
+	));
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nAAAA example.org 60 2001:db8:1:1::5\nsend\nquit\nexit 0\n"
+	);
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv6=::ffff:1.2.3.4"));
+	assert_eq!(status, 400);
+}
+
+#[test]
+fn wrong_password() {
+	let server = Server::start("");
+	let (status, body) = server.get("/update?user=alice&pass=654321&ipv4=1.2.3.4");
+	assert_eq!((status, body.as_str()), (403, "Not authorized"));
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn unknown_user() {
+	let server = Server::start("");
+	let (status, body) = server.get("/update?user=mallory&pass=123456&ipv4=1.2.3.4");
+	assert_eq!((status, body.as_str()), (403, "Not authorized"));
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn missing_credentials() {
+	let server = Server::start("");
+	let (status, _) = server.get("/update?ipv4=1.2.3.4");
+	assert_eq!(status, 400);
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn invalid_address() {
+	let server = Server::start("");
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.400"));
+	assert_eq!(status, 400);
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn unknown_path() {
+	let server = Server::start("");
+	let (status, _) = server.get(&format!("/upgrade?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 404);
+}
+
+#[test]
+fn auto_uses_source_address() {
+	let server = Server::start("");
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=auto"));
+	assert_eq!((status, body.as_str()), (200, "ok"));
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 127.0.0.1\nsend\nquit\nexit 0\n"
+	);
+}
+
+#[test]
+fn auto_with_wrong_address_family() {
+	let server = Server::start("");
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv6=auto"));
+	assert_eq!(status, 400);
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn lan_prefix() {
+	let server = Server::start(
+		r#"
+[users.alice.domains."lan.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 5
+"#,
+	);
+	let (status, _) = server.get(&format!(
+		"/update?{AUTH}&ipv6=2001:db8:1:2::1&ipv6lanprefix=2001:db8:5:600::/56"
+	));
+	assert_eq!(status, 200);
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv6=2001:db8:1:2::1&prefixlen=56"));
+	assert_eq!(status, 200);
+	let record = server.record();
+	assert!(
+		record.contains("AAAA lan.example.org 60 2001:db8:5:600::5\n"),
+		"{record}"
+	);
+	assert!(
+		record.contains("AAAA lan.example.org 60 2001:db8:1::5\n"),
+		"{record}"
+	);
+}
+
+#[test]
+fn forbidden_parameter() {
+	let server = Server::start(
+		r#"
+[users.bob]
+hash = "HASH"
+allow_ipv4 = false
+
+[users.bob.domains."bob.example.org"]
+ttl = 60
+ipv6prefixlen = 128
+ipv6suffix = "::"
+"#
+		.replace("HASH", HASH)
+		.as_str(),
+	);
+	let (status, body) = server.get("/update?user=bob&pass=123456&ipv4=1.2.3.4");
+	assert_eq!((status, body.as_str()), (403, "Parameter ipv4 not allowed"));
+	let (status, _) = server.get("/update?user=bob&pass=123456&ipv6=2001:db8::1");
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nAAAA bob.example.org 60 2001:db8::1\nsend\nquit\nexit 0\n"
+	);
+}
+
+#[test]
+fn disabled_address_family() {
+	let server = Server::start("[features]\nipv6 = false");
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv6=2001:db8::1"));
+	assert_eq!(
+		(status, body.as_str()),
+		(400, "Parameter ipv6 not supported")
+	);
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv6lanprefix=2001:db8::/56"));
+	assert_eq!(
+		(status, body.as_str()),
+		(400, "Parameter ipv6lanprefix not supported")
+	);
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nsend\nquit\nexit 0\n"
+	);
+}
+
+fn shadow_program_config(bin: &str, args: &str) -> String {
+	format!(
+		r#"
+[shadow_program]
+bin = "{bin}"
+args = [{args}]
+stdin_per_zone_update = "send\n"
+final_stdin = "quit\n"
+
+[shadow_program.ipv4]
+stdin = "shadow A {{domain}} {{ipv4}}\n"
+
+[shadow_program.ipv6]
+stdin = "shadow AAAA {{domain}} {{ipv6}}\n"
+"#
+	)
+}
+
+#[test]
+fn shadow_program() {
+	let record = std::env::temp_dir().join(format!("dyndnsd-test-{}-shadow", std::process::id()));
+	let server = Server::start(&shadow_program_config(
+		env!("CARGO_BIN_EXE_mock-update-program"),
+		&format!("\"{}\"", record.display()),
+	));
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nsend\nquit\nexit 0\n"
+	);
+	// The shadow program runs in the background
+	let mut shadow_record = String::new();
+	for _ in 0..100 {
+		shadow_record = fs::read_to_string(&record).unwrap_or_default();
+		if shadow_record.ends_with("exit 0\n") {
+			break;
+		}
+		thread::sleep(Duration::from_millis(20));
+	}
+	assert_eq!(
+		shadow_record,
+		"shadow A example.org 1.2.3.4\nsend\nquit\nexit 0\n"
+	);
+	let _ = fs::remove_file(&record);
+
+	// Failures of the shadow program are only logged
+	let server = Server::start(&shadow_program_config("false", ""));
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	let expected = "The shadow program failed for example.org while the update program succeeded";
+	for _ in 0..100 {
+		if server.log().contains(expected) {
+			break;
+		}
+		thread::sleep(Duration::from_millis(20));
+	}
+	let log = server.log();
+	assert!(log.contains(expected), "{log}");
+	// Depending on when `false` exits, writing the commands or the program itself fails
+	assert!(log.contains("] shadow: "), "{log}");
+}
+
+/// The names of the running child processes of `pid`
+fn children(pid: u32) -> Vec<String> {
+	let Ok(entries) = fs::read_dir("/proc") else {
+		return Vec::new();
+	};
+	entries
+		.filter_map(|entry| fs::read_to_string(entry.ok()?.path().join("stat")).ok())
+		.filter_map(|stat| {
+			// The name is in parentheses and may contain spaces, the parent PID is the second field after it
+			let (name, rest) = stat.split_once(" (")?.1.rsplit_once(") ")?;
+			let mut fields = rest.split(' ');
+			let state = fields.next()?;
+			let parent: u32 = fields.next()?.parse().ok()?;
+			(parent == pid && state != "Z").then(|| name.to_string())
+		})
+		.collect()
+}
+
+#[test]
+fn slow_shadow_program() {
+	// The response does not wait for the shadow program, so it cannot make the request time out
+	let server = Server::start(&format!(
+		"{}\n[limits]\nrequest_timeout = 1",
+		shadow_program_config("sleep", "\"60\"")
+	));
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nsend\nquit\nexit 0\n"
+	);
+	assert!(children(server.pid()).contains(&"sleep".to_string()));
+
+	// A hanging shadow program is killed after the request timeout
+	let expected = "The shadow program did not finish within 1s and was killed";
+	for _ in 0..100 {
+		if server.log().contains(expected) {
+			break;
+		}
+		thread::sleep(Duration::from_millis(50));
+	}
+	let log = server.log();
+	assert!(log.contains(expected), "{log}");
+	thread::sleep(Duration::from_millis(100));
+	assert!(!children(server.pid()).contains(&"sleep".to_string()));
+}
+
+#[test]
+fn update_program_fails() {
+	let server = Server::start_with_program(
+		"",
+		&Program {
+			exit_status: 1,
+			..Program::default()
+		},
+	);
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 500);
+	let id = body
+		.strip_prefix("Internal server error, request ID ")
+		.expect("Generic error message");
+	assert!(server.record().ends_with("exit 1\n"));
+	let log = server.log();
+	assert!(log.contains(&format!("Incoming request {id} ")), "{log}");
+}
+
+#[test]
+fn update_program_times_out() {
+	let server = Server::start_with_program(
+		"[limits]\nrequest_timeout = 1",
+		&Program {
+			sleep: 5,
+			..Program::default()
+		},
+	);
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!((status, body.as_str()), (504, "Timed out"));
+}
+
+#[test]
+fn circuit_breaker_counts_timeouts() {
+	let server = Server::start_with_program(
+		"[limits]\nrequest_timeout = 1\n[circuit_breaker]\nfailures = 1",
+		&Program {
+			sleep: 5,
+			..Program::default()
+		},
+	);
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 504);
+	let (status, _) = server.get("/update?user=alice&pass=wrong&ipv4=1.2.3.4");
+	assert_eq!(status, 403);
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!((status, body.as_str()), (503, "Service unavailable"));
+}
+
+#[test]
+fn query_too_long() {
+	let server = Server::start("[limits]\nmax_query_length = 64");
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4&{}", "a".repeat(64)));
+	assert_eq!(status, 414);
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn dry_run() {
+	let server = Server::start("");
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4&dry_run=1"));
+	assert_eq!((status, body.as_str()), (200, "ok"));
+	assert_eq!(server.record(), "");
+}
+
+#[test]
+fn metrics() {
+	let server = Server::start(
+		r#"
+[metrics]
+max_user_labels = 1
+
+[users.bob]
+hash = "HASH"
+
+[users.bob.domains."bob.example.org"]
+ttl = 60
+ipv6prefixlen = 128
+ipv6suffix = "::"
+"#
+		.replace("HASH", HASH)
+		.as_str(),
+	);
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	server.get("/update?user=alice&pass=654321&ipv4=1.2.3.4");
+	server.get("/update?user=bob&pass=123456&ipv4=1.2.3.4");
+	server.get("/update?user=mallory&pass=123456&ipv4=1.2.3.4");
+	let (status, body) = server.get("/metrics");
+	assert_eq!(status, 200);
+	assert!(
+		body.contains("dyndnsd_updates_total{result=\"success\"} 2\n"),
+		"{body}"
+	);
+	assert!(
+		body.contains("dyndnsd_updates_total{result=\"failure\"} 2\n"),
+		"{body}"
+	);
+	assert!(
+		body.contains("dyndnsd_user_updates_total{user=\"alice\",result=\"success\"} 1\n"),
+		"{body}"
+	);
+	assert!(
+		body.contains("dyndnsd_user_updates_total{user=\"alice\",result=\"failure\"} 1\n"),
+		"{body}"
+	);
+	assert!(!body.contains("bob"), "{body}");
+	assert!(!body.contains("mallory"), "{body}");
+}
+
+#[test]
+fn metrics_per_backend() {
+	let server = Server::start(
+		r#"
+[metrics]
+zones = ["example.org"]
+max_backend_labels = 1
+
+[users.bob]
+hash = "HASH"
+
+[users.bob.domains."bob.example.com"]
+ttl = 60
+ipv6prefixlen = 128
+ipv6suffix = "::"
+"#
+		.replace("HASH", HASH)
+		.as_str(),
+	);
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.5"));
+	server.get("/update?user=bob&pass=123456&ipv4=1.2.3.4");
+	let (_, body) = server.get("/metrics");
+	let labels = "backend=\"mock-update-program\",zone=\"example.org\"";
+	assert!(
+		body.contains(&format!(
+			"dyndnsd_backend_runs_total{{{labels},result=\"success\"}} 2\n"
+		)),
+		"{body}"
+	);
+	assert!(
+		body.contains(&format!(
+			"dyndnsd_backend_duration_seconds_count{{{labels}}} 2\n"
+		)),
+		"{body}"
+	);
+	// The limit of label sets is reached
+	assert!(!body.contains("zone=\"other\""), "{body}");
+	// The counters per user are opt-in
+	assert!(!body.contains("user=\"alice\""), "{body}");
+}
+
+#[test]
+fn metrics_disabled() {
+	let server = Server::start("");
+	let (status, _) = server.get("/metrics");
+	assert_eq!(status, 404);
+}
+
+#[test]
+#[allow(clippy::literal_string_with_formatting_args)]
+fn response_templates() {
+	let server = Server::start(
+		r#"
+[responses]
+success = "good {ipv4} {ipv6} {domain}"
+error = "{status} {message}"
+"#,
+	);
+	let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4&ipv6=2001:db8:1:2::1"));
+	assert_eq!(
+		(status, body.as_str()),
+		(200, "good 1.2.3.4 2001:db8:1:1::5 example.org")
+	);
+	let (status, body) = server.get("/update?user=alice&pass=654321&ipv4=1.2.3.4");
+	assert_eq!((status, body.as_str()), (403, "403 Not authorized"));
+}
+
+#[test]
+fn cors() {
+	let server = Server::start(
+		r#"
+[cors]
+allowed_origins = ["https://dashboard.example.org"]
+"#,
+	);
+	let origin = ("Origin", "https://dashboard.example.org");
+	let (status, head, _) = server.request(
+		"OPTIONS",
+		"/update",
+		&[origin, ("Access-Control-Request-Method", "GET")],
+	);
+	assert_eq!(status, 200);
+	let head = head.to_lowercase();
+	assert!(
+		head.contains("access-control-allow-origin: https://dashboard.example.org"),
+		"{head}"
+	);
+
+	let (status, head, body) =
+		server.request("GET", &format!("/update?{AUTH}&ipv4=1.2.3.4"), &[origin]);
+	assert_eq!((status, body.as_str()), (200, "ok"));
+	assert!(
+		head.to_lowercase()
+			.contains("access-control-expose-headers"),
+		"{head}"
+	);
+
+	let (status, _, _) = server.request(
+		"GET",
+		&format!("/update?{AUTH}&ipv4=1.2.3.4"),
+		&[("Origin", "https://evil.example")],
+	);
+	assert_eq!(status, 403);
+}
+
+#[test]
+fn web_ui() {
+	let server = Server::start("web_ui = true");
+	let (status, head, _) = server.request("GET", "/", &[]);
+	assert_eq!(status, 401);
+	assert!(
+		head.to_lowercase().contains("www-authenticate: basic"),
+		"{head}"
+	);
+	// alice:654321
+	let (status, _, _) = server.request("GET", "/", &[("Authorization", "Basic YWxpY2U6NjU0MzIx")]);
+	assert_eq!(status, 401);
+
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	// alice:123456
+	let (status, _, body) =
+		server.request("GET", "/", &[("Authorization", "Basic YWxpY2U6MTIzNDU2")]);
+	assert_eq!(status, 200);
+	assert!(
+		body.contains("<tr><td>example.org</td><td>1.2.3.4</td><td>unknown</td></tr>"),
+		"{body}"
+	);
+	assert!(body.contains(r#"method="post""#), "{body}");
+
+	// The form sends the password in the body instead of the URL
+	let form = "user=alice&pass=123456&ipv4=1.2.3.5";
+	let mut stream = server.connect();
+	write!(
+		stream,
+		"POST /update HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{form}",
+		form.len()
+	)
+	.expect("Cannot send request");
+	let mut response = String::new();
+	stream
+		.read_to_string(&mut response)
+		.expect("Cannot read response");
+	assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+	assert!(
+		server
+			.record()
+			.ends_with("A example.org 60 1.2.3.5\nsend\nquit\nexit 0\n"),
+		"{}",
+		server.record()
+	);
+}
+
+#[test]
+fn status() {
+	let server = Server::start("history_length = 2");
+	for ipv4 in ["1.2.3.4", "1.2.3.5", "1.2.3.6"] {
+		server.get(&format!("/update?{AUTH}&ipv4={ipv4}"));
+	}
+	let (status, body) = server.get(&format!("/status?{AUTH}"));
+	assert_eq!(status, 200);
+	let json: serde_json::Value = serde_json::from_str(&body).expect("Status is not JSON");
+	let history = json["domains"]["example.org"]
+		.as_array()
+		.expect("No history for example.org");
+	assert_eq!(history.len(), 2, "{body}");
+	assert_eq!(history[0]["ipv4"], "1.2.3.5");
+	assert_eq!(history[1]["ipv4"], "1.2.3.6");
+	assert_eq!(history[1]["success"], true);
+	assert_eq!(history[1]["client"], "127.0.0.1");
+
+	let (status, _) = server.get("/status?user=alice&pass=654321");
+	assert_eq!(status, 403);
+	let (status, _) = server.get(&format!("/status?{AUTH}&domain=other.example.org"));
+	assert_eq!(status, 403);
+}
+
+#[test]
+fn forwarding_headers() {
+	let server = Server::start_with_listen(
+		"[checkip]",
+		"trusted_proxy_depth = 1\nforwarded_header = \"forwarded\"",
+	);
+	let forwarded = r#"for=192.0.2.60;proto=http, For="[2001:db8::17]:4711""#;
+	let (status, _, body) = server.request("GET", "/checkip", &[("Forwarded", forwarded)]);
+	assert_eq!((status, body.as_str()), (200, "2001:db8::17\n"));
+	// Not sent through the proxy, or with a header a client can trigger errors with
+	assert_eq!(server.get("/checkip").0, 400);
+	let (status, _, _) = server.request("GET", "/checkip", &[("Forwarded", ";")]);
+	assert_eq!(status, 400);
+	assert!(
+		server.log().contains("Invalid Forwarded header"),
+		"{}",
+		server.log()
+	);
+
+	let server = Server::start_with_listen(
+		"[checkip]",
+		"trusted_proxy_depth = 1\nforwarded_header = \"x-forwarded-for\"",
+	);
+	// The proxy does not write the Forwarded header, so the client sent it
+	let (_, _, body) = server.request(
+		"GET",
+		"/checkip",
+		&[
+			("Forwarded", "for=192.0.2.1"),
+			("X-Forwarded-For", "203.0.113.1, 198.51.100.2"),
+		],
+	);
+	assert_eq!(body, "198.51.100.2\n");
+	let (status, _, _) = server.request("GET", "/checkip", &[("Forwarded", "for=192.0.2.1")]);
+	assert_eq!(status, 400);
+
+	let (status, _, _) = server.request(
+		"GET",
+		&format!("/update?{AUTH}&ipv4=auto"),
+		&[("X-Forwarded-For", "198.51.100.7")],
+	);
+	assert_eq!(status, 200);
+	assert!(
+		server.record().contains("A example.org 60 198.51.100.7\n"),
+		"{}",
+		server.record()
+	);
+
+	let server = Server::start_with_listen(
+		"[checkip]",
+		"trusted_proxy_depth = 2\nforwarded_header = \"forwarded\"",
+	);
+	let (_, _, body) = server.request("GET", "/checkip", &[("Forwarded", forwarded)]);
+	assert_eq!(body, "192.0.2.60\n");
+	let (status, _, _) = server.request("GET", "/checkip", &[("Forwarded", "for=198.51.100.1")]);
+	assert_eq!(status, 400);
+}
+
+#[test]
+fn proxy_protocol_per_listener() {
+	let socket =
+		std::env::temp_dir().join(format!("dyndnsd-test-{}-proxy.sock", std::process::id()));
+	let server = Server::start_with_listen(
+		"[checkip]",
+		&format!("proxy_protocol = [true]\nunix = \"{}\"", socket.display()),
+	);
+	let mut stream = server.connect();
+	stream
+		.write_all(b"PROXY TCP4 192.0.2.43 127.0.0.1 47011 9841\r\nGET /checkip HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+		.expect("Cannot send request");
+	let mut response = String::new();
+	stream
+		.read_to_string(&mut response)
+		.expect("Cannot read response");
+	assert!(response.ends_with("\r\n\r\n192.0.2.43\n"), "{response}");
+
+	// Connections without the header are closed
+	let mut stream = server.connect();
+	stream
+		.write_all(b"GET /checkip HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+		.expect("Cannot send request");
+	let mut response = String::new();
+	let _ = stream.read_to_string(&mut response);
+	assert_eq!(response, "");
+
+	// The Unix socket does not expect the header
+	let mut stream = UnixStream::connect(&socket).expect("Cannot connect to the Unix socket");
+	stream
+		.write_all(
+			format!("GET /update?{AUTH}&ipv4=1.2.3.4 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+				.as_bytes(),
+		)
+		.expect("Cannot send request");
+	let mut response = String::new();
+	stream
+		.read_to_string(&mut response)
+		.expect("Cannot read response");
+	assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+	drop(server);
+	let _ = fs::remove_file(&socket);
+}
+
+#[test]
+fn web_ui_disabled() {
+	let server = Server::start("");
+	let (status, _) = server.get("/");
+	assert_eq!(status, 404);
+}
+
+#[test]
+fn site_shares_lan_prefix() {
+	let server = Server::start(&format!(
+		r#"
+[users.bob]
+hash = "{HASH}"
+site = "home"
+
+[users.bob.domains."bob.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 5
+
+[users.carol]
+hash = "{HASH}"
+site = "home"
+
+[users.carol.domains."carol.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 6
+
+[users.carol.domains."static.example.org"]
+ttl = 60
+ipv6prefixlen = 64
+ipv6suffix = 7
+"#
+	));
+	let (status, _) = server.get(
+		"/update?user=bob&pass=123456&ipv4=1.2.3.4&ipv6=2001:db8:1:2::1&ipv6lanprefix=2001:db8:5:600::/56",
+	);
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nA bob.example.org 60 1.2.3.4\nAAAA bob.example.org 60 2001:db8:5:600::5\nsend\nAAAA carol.example.org 60 2001:db8:5:600::6\nsend\nquit\nexit 0\n"
+	);
+
+	// Without a LAN prefix, only the domains of the user itself are updated
+	let before = server.record().len();
+	let (status, _) = server.get("/update?user=carol&pass=123456&ipv4=5.6.7.8");
+	assert_eq!(status, 200);
+	let record = server.record();
+	assert!(!record[before..].contains("bob.example.org"), "{record}");
+}
+
+#[test]
+fn parameter_aliases() {
+	let server = Server::start(
+		r#"
+[parameter_aliases]
+ip = "ipv4"
+myip6 = "ipv6"
+"#,
+	);
+	let (status, body) = server.get(&format!("/update?{AUTH}&ip=1.2.3.4&myip6=2001:db8:1:2::1"));
+	assert_eq!((status, body.as_str()), (200, "ok"));
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nAAAA example.org 60 2001:db8:1:1::5\nsend\nquit\nexit 0\n"
+	);
+	let (status, _) = server.get(&format!("/update?{AUTH}&ip=1.2.3.4&ipv4=1.2.3.4"));
+	assert_eq!(status, 400);
+}
+
+#[test]
+fn dyndns2_hostnames() {
+	let server = Server::start(
+		r#"
+[users.alice.domains."home.example.org"]
+ttl = 60
+ipv6prefixlen = 48
+ipv6suffix = 6
+"#,
+	);
+	let (status, body) = server.get(&format!(
+		"/update?{AUTH}&system=dyndns&hostname=example.org,other.example.org&ipv4=1.2.3.4"
+	));
+	assert_eq!((status, body.as_str()), (200, "good 1.2.3.4\nnohost"));
+	assert_eq!(
+		server.record(),
+		"begin\nA example.org 60 1.2.3.4\nsend\nquit\nexit 0\n"
+	);
+
+	let (status, body) = server.get(&format!(
+		"/update?{AUTH}&hostname=other.example.org&ipv4=1.2.3.4"
+	));
+	assert_eq!((status, body.as_str()), (200, "nohost"));
+	let (status, body) = server.get(&format!("/update?{AUTH}&system=statdns&ipv4=1.2.3.4"));
+	assert_eq!((status, body.as_str()), (400, "badsys"));
+	assert_eq!(server.record().matches("begin").count(), 1);
+}
+
+#[test]
+fn mqtt() {
+	let broker = TcpListener::bind("127.0.0.1:0").expect("Cannot bind the MQTT broker");
+	let broker_addr = broker.local_addr().expect("Broker has no address");
+	let received = thread::spawn(move || {
+		let (mut stream, _) = broker.accept().expect("No MQTT connection");
+		// The CONNECT packet is short, so its length fits into one byte
+		let mut header = [0; 2];
+		stream.read_exact(&mut header).expect("Cannot read CONNECT");
+		let mut connect = vec![0; usize::from(header[1])];
+		stream
+			.read_exact(&mut connect)
+			.expect("Cannot read CONNECT");
+		stream
+			.write_all(&[0x20, 0x02, 0x00, 0x00])
+			.expect("Cannot send CONNACK");
+		let mut rest = Vec::new();
+		stream.read_to_end(&mut rest).expect("Cannot read PUBLISH");
+		(header, rest)
+	});
+	let server = Server::start(&format!(
+		r#"
+[mqtt]
+server = "{broker_addr}"
+topic = "home/{{domain}}"
+"#
+	));
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	let (header, rest) = received.join().expect("Broker failed");
+	assert_eq!(header[0], 0x10);
+	let rest = String::from_utf8_lossy(&rest);
+	assert!(rest.starts_with('\u{31}'), "{rest}");
+	assert!(
+		rest.contains(
+			r#"home/example.org{"domain":"example.org","ipv4":"1.2.3.4","ipv6":null,"ttl":60}"#
+		),
+		"{rest}"
+	);
+}
+
+#[test]
+fn dns_notify() {
+	let secondary = UdpSocket::bind("127.0.0.1:0").expect("Cannot bind the secondary");
+	let secondary_addr = secondary.local_addr().expect("Secondary has no address");
+	let received = thread::spawn(move || {
+		let mut buf = [0; 512];
+		let (length, peer) = secondary.recv_from(&mut buf).expect("No NOTIFY received");
+		let mut response = buf[..12].to_vec();
+		response[2] |= 0x80;
+		secondary
+			.send_to(&response, peer)
+			.expect("Cannot answer NOTIFY");
+		buf[..length].to_vec()
+	});
+	let server = Server::start(&format!(
+		r#"
+[notify]
+servers = ["{secondary_addr}"]
+zones = ["org", "example.org"]
+"#
+	));
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	let notify = received.join().expect("Secondary failed");
+	// Opcode NOTIFY with the authoritative answer flag
+	assert_eq!(notify[2], 0x24);
+	// The most specific zone with type SOA and class IN
+	assert_eq!(&notify[12..], b"\x07example\x03org\0\0\x06\0\x01");
+}
+
+#[test]
+fn statsd() {
+	let statsd = UdpSocket::bind("127.0.0.1:0").expect("Cannot bind the StatsD server");
+	statsd
+		.set_read_timeout(Some(Duration::from_secs(5)))
+		.expect("Cannot set timeout");
+	let server = Server::start(&format!(
+		r#"
+[statsd]
+server = "{}"
+prefix = "test"
+"#,
+		statsd.local_addr().expect("StatsD server has no address")
+	));
+	let receive = || {
+		let mut buf = [0; 512];
+		let length = statsd.recv(&mut buf).expect("No metrics received");
+		String::from_utf8(buf[..length].to_vec()).expect("Metrics are not UTF-8")
+	};
+
+	let (status, _) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	assert_eq!(status, 200);
+	let metrics = receive();
+	assert!(metrics.starts_with("test.updates.success:1|c\ntest.request_duration:"));
+	assert!(metrics.ends_with("|ms"));
+
+	let (status, _) = server.get("/update?user=alice&pass=wrong&ipv4=1.2.3.4");
+	assert_eq!(status, 403);
+	assert!(receive().starts_with("test.updates.failure:1|c\n"));
+}
+
+#[test]
+fn events_file() {
+	let events_file =
+		std::env::temp_dir().join(format!("dyndnsd-test-{}-events", std::process::id()));
+	let server = Server::start(&format!("events_file = \"{}\"", events_file.display()));
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	server.get("/update?user=alice&pass=wrong&ipv4=1.2.3.4");
+
+	// The events are written in the background
+	let mut events = String::new();
+	for _ in 0..100 {
+		events = fs::read_to_string(&events_file).unwrap_or_default();
+		if events.lines().count() == 2 {
+			break;
+		}
+		thread::sleep(Duration::from_millis(20));
+	}
+	let events: Vec<serde_json::Value> = events
+		.lines()
+		.map(|line| serde_json::from_str(line).expect("Event is not JSON"))
+		.collect();
+	assert_eq!(events.len(), 2, "{events:?}");
+	assert_eq!(events[0]["user"], "alice");
+	assert_eq!(events[0]["status"], 200);
+	assert_eq!(
+		events[0]["domains"],
+		serde_json::json!([{"domain": "example.org", "ttl": 60, "ipv4": "1.2.3.4", "ipv6": null, "success": true}])
+	);
+	assert_eq!(events[1]["status"], 403);
+	assert_eq!(events[1]["success"], false);
+	assert_eq!(events[1]["domains"], serde_json::json!([]));
+	let _ = fs::remove_file(&events_file);
+}
+
+#[test]
+fn events_file_of_duplicate() {
+	let events_file = std::env::temp_dir().join(format!(
+		"dyndnsd-test-{}-duplicate-events",
+		std::process::id()
+	));
+	let server = Server::start(&format!(
+		"events_file = \"{}\"\ndedupe_window = 60",
+		events_file.display()
+	));
+	for _ in 0..2 {
+		let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+		assert_eq!((status, body.as_str()), (200, "ok"));
+	}
+
+	let mut events = String::new();
+	for _ in 0..100 {
+		events = fs::read_to_string(&events_file).unwrap_or_default();
+		if events.lines().count() == 2 {
+			break;
+		}
+		thread::sleep(Duration::from_millis(20));
+	}
+	let events: Vec<serde_json::Value> = events
+		.lines()
+		.map(|line| serde_json::from_str(line).expect("Event is not JSON"))
+		.collect();
+	assert_eq!(events.len(), 2, "{events:?}");
+	for event in &events {
+		assert_eq!(
+			event["domains"],
+			serde_json::json!([{"domain": "example.org", "ttl": 60, "ipv4": "1.2.3.4", "ipv6": null, "success": true}])
+		);
+	}
+	assert_eq!(server.record().matches("exit 0").count(), 1);
+	let _ = fs::remove_file(&events_file);
+}
+
+#[test]
+fn events_file_without_reader() {
+	let fifo = std::env::temp_dir().join(format!("dyndnsd-test-{}-fifo", std::process::id()));
+	nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).expect("Cannot create FIFO");
+	let server = Server::start_with_program(
+		&format!(
+			"events_file = \"{}\"\n[limits]\nrequest_timeout = 1",
+			fifo.display()
+		),
+		&Program::default(),
+	);
+	for _ in 0..3 {
+		let (status, body) = server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+		assert_eq!((status, body.as_str()), (200, "ok"));
+	}
+	let _ = fs::remove_file(&fifo);
+}
+
+#[test]
+fn checkip() {
+	let server = Server::start("[checkip]\nrate_limit = 3");
+	assert_eq!(server.get("/checkip"), (200, "127.0.0.1\n".to_string()));
+	assert_eq!(
+		server.get("/checkip?format=json"),
+		(200, r#"{"ip":"127.0.0.1"}"#.to_string())
+	);
+	let (status, _, body) = server.request("GET", "/checkip", &[("Accept", "application/json")]);
+	assert_eq!((status, body.as_str()), (200, r#"{"ip":"127.0.0.1"}"#));
+	let (status, head, _) = server.request("GET", "/checkip", &[]);
+	assert_eq!(status, 429);
+	assert!(head.to_lowercase().contains("retry-after: "), "{head}");
+
+	let server = Server::start("");
+	assert_eq!(server.get("/checkip").0, 404);
+}
+
+#[test]
+fn ipv4_source_check() {
+	let server = Server::start("ipv4_source_check = \"warn\"");
+	assert_eq!(server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")).0, 200);
+	assert!(server.log().contains("differs from the source address"));
+
+	let server = Server::start("ipv4_source_check = \"reject\"");
+	assert_eq!(
+		server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")),
+		(
+			403,
+			"IPv4 address does not match the source address".to_string()
+		)
+	);
+	assert_eq!(server.get(&format!("/update?{AUTH}&ipv4=127.0.0.1")).0, 200);
+	assert_eq!(
+		server
+			.get(&format!("/update?{AUTH}&ipv6=2001:db8:1:2::1"))
+			.0,
+		200
+	);
+	assert!(!server.record().contains("1.2.3.4"));
+}
+
+#[test]
+fn repeated_wrong_password_is_not_verified_again() {
+	let cached = "the same password was wrong";
+	// Disabled by default
+	let server = Server::start("");
+	for _ in 0..2 {
+		assert_eq!(
+			server.get("/update?user=alice&pass=wrong&ipv4=1.2.3.4").0,
+			403
+		);
+	}
+	assert!(!server.log().contains(cached));
+
+	let server = Server::start("auth_failure_window = 60");
+	assert_eq!(
+		server.get("/update?user=alice&pass=wrong&ipv4=1.2.3.4").0,
+		403
+	);
+	assert!(!server.log().contains(cached));
+	assert_eq!(
+		server.get("/update?user=alice&pass=wrong&ipv4=1.2.3.4").0,
+		403
+	);
+	assert!(server.log().contains(cached));
+	// Other passwords are still verified
+	assert_eq!(server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")).0, 200);
+}
+
+#[test]
+fn changes_in_response() {
+	let server = Server::start("[responses]\nchanges = \"text\"");
+	assert_eq!(
+		server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")),
+		(200, "ok\nexample.org A unknown -> 1.2.3.4".to_string())
+	);
+	assert_eq!(
+		server.get(&format!("/update?{AUTH}&ipv4=1.2.3.5&ipv6=2001:db8:1:2::1")),
+		(
+			200,
+			"ok\nexample.org A 1.2.3.4 -> 1.2.3.5\nexample.org AAAA unknown -> 2001:db8:1:1::5"
+				.to_string()
+		)
+	);
+
+	let server = Server::start("[responses]\nchanges = \"json\"");
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	let (status, head, body) = server.request("GET", &format!("/update?{AUTH}&ipv4=1.2.3.5"), &[]);
+	assert_eq!(status, 200);
+	assert!(head.contains("content-type: application/json"), "{head}");
+	let body: serde_json::Value = serde_json::from_str(&body).expect("Body is not JSON");
+	assert_eq!(
+		body,
+		serde_json::json!({
+			"result": "ok",
+			"domains": [{"domain": "example.org", "ipv4": {"old": "1.2.3.4", "new": "1.2.3.5"}, "ipv6": null}],
+		})
+	);
+}
+
+#[test]
+fn quota() {
+	let server = Server::start(
+		r#"
+[users.alice.quota]
+max_domains = 1
+min_ttl = 300
+max_updates_per_day = 2
+
+[users.alice.domains."example.com"]
+ttl = 60
+ipv6prefixlen = 48
+ipv6suffix = "::1"
+"#,
+	);
+	// Only example.com, the first domain by name, with the minimum TTL
+	assert_eq!(server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")).0, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nA example.com 300 1.2.3.4\nsend\nquit\nexit 0\n"
+	);
+	assert_eq!(server.get(&format!("/update?{AUTH}&ipv4=1.2.3.5")).0, 200);
+	assert_eq!(
+		server.get(&format!("/update?{AUTH}&ipv4=1.2.3.6")),
+		(429, "Quota exceeded".to_string())
+	);
+	assert!(!server.record().contains("1.2.3.6"));
+}
+
+#[test]
+fn quota_includes_site_domains() {
+	let server = Server::start(&format!(
+		r#"
+[users.bob]
+hash = "{HASH}"
+site = "home"
+
+[users.bob.quota]
+max_domains = 2
+min_ttl = 300
+
+[users.bob.domains."bob.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 5
+
+[users.carol]
+hash = "{HASH}"
+site = "home"
+
+[users.carol.domains."carol.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 6
+
+[users.carol.domains."dave.example.org"]
+ttl = 60
+ipv6prefixlen = "lan"
+ipv6suffix = 7
+"#
+	));
+	let (status, _) = server
+		.get("/update?user=bob&pass=123456&ipv6=2001:db8:1:2::1&ipv6lanprefix=2001:db8:5:600::/56");
+	assert_eq!(status, 200);
+	assert_eq!(
+		server.record(),
+		"begin\nAAAA bob.example.org 300 2001:db8:5:600::5\nsend\nAAAA carol.example.org 300 2001:db8:5:600::6\nsend\nquit\nexit 0\n"
+	);
+}
+
+/// A `MaxMind` database for IPv4 with a single node, putting 0.0.0.0/1 (including 127.0.0.1) into `country`
+fn write_country_database(path: &std::path::Path, country: &str) {
+	fn string(s: &str) -> Vec<u8> {
+		let length = u8::try_from(s.len()).expect("String is too long");
+		assert!(length < 29);
+		[&[0x40 | length], s.as_bytes()].concat()
+	}
+	// Left record pointing to the start of the data section (node count + 16), right record meaning "not found"
+	let mut database = vec![0, 0, 17, 0, 0, 1];
+	database.extend([0; 16]);
+	database.push(0xe1);
+	database.extend(string("country"));
+	database.push(0xe1);
+	database.extend(string("iso_code"));
+	database.extend(string(country));
+	database.extend(b"\xab\xcd\xefMaxMind.com");
+	database.push(0xe9);
+	let metadata: [(&str, &[u8]); 9] = [
+		("binary_format_major_version", &[0xa1, 2]),
+		("binary_format_minor_version", &[0xa0]),
+		("build_epoch", &[0x00, 0x02]),
+		("database_type", &[0x44, b'T', b'e', b's', b't']),
+		("description", &[0xe0]),
+		("ip_version", &[0xa1, 4]),
+		("languages", &[0x00, 0x04]),
+		("node_count", &[0xc1, 1]),
+		("record_size", &[0xa1, 24]),
+	];
+	for (key, value) in metadata {
+		database.extend(string(key));
+		database.extend(value);
+	}
+	fs::write(path, database).expect("Cannot write GeoIP database");
+}
+
+#[test]
+fn geoip() {
+	let database =
+		std::env::temp_dir().join(format!("dyndnsd-test-{}-geoip.mmdb", std::process::id()));
+	write_country_database(&database, "DE");
+
+	let server = Server::start(&format!(
+		"[geoip]\ndatabase = \"{}\"\nallowed_countries = [\"de\", \"at\"]",
+		database.display()
+	));
+	assert_eq!(
+		server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")),
+		(200, "ok".to_string())
+	);
+
+	let server = Server::start(&format!(
+		"[geoip]\ndatabase = \"{}\"\nallowed_countries = [\"FR\"]",
+		database.display()
+	));
+	assert_eq!(
+		server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4")),
+		(403, "Country not allowed".to_string())
+	);
+	assert!(server.log().contains("in country DE, allowed are FR"));
+	assert_eq!(server.record(), "");
+	let _ = fs::remove_file(&database);
+}
+
+#[test]
+fn log_file_is_reopened_on_sigusr1() {
+	let log_file = std::env::temp_dir().join(format!("dyndnsd-test-{}-log", std::process::id()));
+	let rotated = log_file.with_extension("1");
+	let server = Server::start(&format!("log_file = \"{}\"", log_file.display()));
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	fs::rename(&log_file, &rotated).expect("Cannot rotate log file");
+	let status = Command::new("kill")
+		.args(["-USR1", &server.pid().to_string()])
+		.status()
+		.expect("Cannot run kill");
+	assert!(status.success());
+
+	// The signal is handled asynchronously, so wait for the new file
+	for _ in 0..100 {
+		if log_file.exists() {
+			break;
+		}
+		thread::sleep(Duration::from_millis(20));
+	}
+	server.get(&format!("/update?{AUTH}&ipv4=1.2.3.4"));
+	let old = fs::read_to_string(&rotated).expect("Cannot read rotated log file");
+	let new = fs::read_to_string(&log_file).expect("Cannot read new log file");
+	assert!(old.contains("Incoming request"), "{old}");
+	assert!(new.contains("Reopened the log file"), "{new}");
+	assert!(new.contains("Incoming request"), "{new}");
+	let _ = fs::remove_file(&log_file);
+	let _ = fs::remove_file(&rotated);
+}
+
+/// Whether the server closed the connection within a few seconds
+fn is_closed(stream: &mut TcpStream) -> bool {
+	stream
+		.set_read_timeout(Some(Duration::from_secs(5)))
+		.expect("Cannot set read timeout");
+	let mut rest = Vec::new();
+	stream.read_to_end(&mut rest).is_ok()
+}
+
+#[test]
+fn slow_header_is_cut_off() {
+	let server = Server::start("[limits]\nheader_read_timeout = 1");
+	let mut stream = server.connect();
+	stream
+		.write_all(b"GET /update HTTP/1.1\r\n")
+		.expect("Cannot send request");
+	assert!(is_closed(&mut stream));
+}
+
+#[test]
+fn idle_connection_is_closed() {
+	let server = Server::start("[limits]\nidle_timeout = 1");
+	let mut stream = server.connect();
+	write!(
+		stream,
+		"GET /update?{AUTH}&ipv4=1.2.3.4 HTTP/1.1\r\nHost: localhost\r\n\r\n"
+	)
+	.expect("Cannot send request");
+	let mut response = [0; 12];
+	stream
+		.read_exact(&mut response)
+		.expect("Cannot read response");
+	assert_eq!(&response, b"HTTP/1.1 200");
+	assert!(is_closed(&mut stream));
+}
+
+/// Open a connection and send a request on it, returning the connection if the request was answered
+fn try_request(server: &Server) -> Option<TcpStream> {
+	let mut stream = server.connect();
+	write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").ok()?;
+	let mut status_line = [0; 12];
+	stream.read_exact(&mut status_line).ok()?;
+	Some(stream)
+}
+
+/// Retry `try_request()` for a few seconds, since closed connections are only noticed by the server after a moment
+fn request_eventually(server: &Server) -> Option<TcpStream> {
+	(0..50).find_map(|_| {
+		try_request(server).or_else(|| {
+			thread::sleep(Duration::from_millis(100));
+			None
+		})
+	})
+}
+
+#[test]
+fn connections_per_ip() {
+	let server = Server::start("[limits]\nmax_connections_per_ip = 1");
+	// The connection which checked whether the server is listening may still be counted
+	let first = request_eventually(&server).expect("First connection was refused");
+	assert!(try_request(&server).is_none());
+	drop(first);
+	assert!(request_eventually(&server).is_some());
+}
+
+#[test]
+fn connections_per_ip_behind_trusted_proxies() {
+	// All connections come from the proxy, so they are not limited
+	let server = Server::start_with_listen(
+		"[limits]\nmax_connections_per_ip = 1",
+		"trusted_proxy_depth = 1\nforwarded_header = \"forwarded\"",
+	);
+	let first = request_eventually(&server).expect("First connection was refused");
+	assert!(try_request(&server).is_some());
+	drop(first);
+}